@@ -0,0 +1,209 @@
+//! Telling whether a carrier-grade NAT sits between us and the internet
+//!
+//! Carriers short of IPv4 addresses put many customers behind one shared
+//! NAT (RFC 6888). Customers behind one can't accept incoming connections,
+//! so port forwarding on their own router has no effect. The carrier's NAT
+//! shows up as a hop between the router and the internet. The router's
+//! external address is then in the `100.64.0.0/10` shared address space
+//! (RFC 6598), or some other address that isn't the one the internet sees.
+//!
+//! The evidence compared is:
+//!
+//! - The local address traffic to the internet leaves this machine from
+//! - The external address the router reports over NAT-PMP, PCP or `UPnP`
+//! - The WAN address remote services see
+
+use crate::SourceAddresses;
+use core::fmt;
+use core::fmt::{Display, Formatter};
+use miette::{IntoDiagnostic, Result, miette};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+/// Where the egress probe pretends to send to; nothing is actually sent
+const PROBE_DESTINATION: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 53);
+
+/// The addresses gathered to decide whether there is a carrier-grade NAT
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Evidence {
+    /// The IPv4 addresses traffic to the internet leaves this machine from
+    pub local: Vec<Ipv4Addr>,
+    /// The external address the router reports, if it reports one
+    pub router: Option<Ipv4Addr>,
+    /// The IPv4 addresses remote services see
+    pub wan: Vec<Ipv4Addr>,
+}
+
+impl Evidence {
+    /// Decide whether there is a carrier-grade NAT, and why
+    #[must_use]
+    pub fn verdict(&self) -> Verdict {
+        if let Some(local) = self.local.iter().find(|ip| is_shared_address(**ip)) {
+            return Verdict::SharedLocalAddress(*local);
+        }
+
+        match self.router {
+            Some(router) if self.wan.contains(&router) => Verdict::RouterHasWanAddress,
+            Some(router) if is_shared_address(router) || router.is_private() => {
+                Verdict::RouterAddressNotPublic(router)
+            }
+            Some(router) => Verdict::RouterAddressNotWan(router),
+            None if self.local.iter().any(|ip| self.wan.contains(ip)) => {
+                Verdict::LocalHasWanAddress
+            }
+            None => Verdict::Unknown,
+        }
+    }
+}
+
+/// Whether there is a carrier-grade NAT, and the evidence that decided it
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Verdict {
+    /// This machine has an address in the shared address space itself
+    SharedLocalAddress(Ipv4Addr),
+    /// The router's external address is shared or private
+    RouterAddressNotPublic(Ipv4Addr),
+    /// The router's external address is public, but not the WAN address
+    RouterAddressNotWan(Ipv4Addr),
+    /// The router's external address is the WAN address
+    RouterHasWanAddress,
+    /// This machine has the WAN address, so there is no NAT at all
+    LocalHasWanAddress,
+    /// The router did not report its external address, and this machine
+    /// does not have the WAN address
+    Unknown,
+}
+
+impl Verdict {
+    /// Whether there is a carrier-grade NAT, or `None` if it can't be told
+    #[must_use]
+    pub const fn is_behind_cgnat(self) -> Option<bool> {
+        match self {
+            Self::SharedLocalAddress(_)
+            | Self::RouterAddressNotPublic(_)
+            | Self::RouterAddressNotWan(_) => Some(true),
+            Self::RouterHasWanAddress | Self::LocalHasWanAddress => Some(false),
+            Self::Unknown => None,
+        }
+    }
+}
+
+impl Display for Verdict {
+    /// Format the verdict as yes, no or unknown, followed by the reason
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SharedLocalAddress(ip) => {
+                write!(f, "yes, {ip} is in the shared address space carriers use")
+            }
+            Self::RouterAddressNotPublic(ip) => {
+                write!(f, "yes, the router's external address {ip} is not public")
+            }
+            Self::RouterAddressNotWan(ip) => write!(
+                f,
+                "yes, the router's external address {ip} is not the WAN address"
+            ),
+            Self::RouterHasWanAddress => {
+                write!(f, "no, the router's external address is the WAN address")
+            }
+            Self::LocalHasWanAddress => write!(f, "no, this machine has the WAN address"),
+            Self::Unknown => write!(f, "unknown, the router did not report its external address"),
+        }
+    }
+}
+
+/// Find the local IPv4 address traffic to the internet leaves from
+///
+/// Only this address is evidence; VPNs such as Tailscale also put addresses
+/// from the shared address space on their own interfaces. A UDP socket is
+/// connected so the routing table picks the address, but nothing is sent.
+///
+/// # Errors
+///
+/// Returns an error if there is no IPv4 route to the internet
+pub async fn egress_address(source: SourceAddresses) -> Result<Ipv4Addr> {
+    if let Some(ip) = source.ipv4 {
+        return Ok(ip);
+    }
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))
+        .await
+        .into_diagnostic()?;
+    socket
+        .connect(PROBE_DESTINATION)
+        .await
+        .map_err(|e| miette!("There is no IPv4 route to the internet: {e}"))?;
+    match socket.local_addr().into_diagnostic()?.ip() {
+        IpAddr::V4(ip) => Ok(ip),
+        IpAddr::V6(ip) => Err(miette!("IPv4 traffic would leave from {ip}")),
+    }
+}
+
+/// Whether `ip` is in the shared address space carriers use for NAT,
+/// `100.64.0.0/10` (RFC 6598)
+#[must_use]
+pub const fn is_shared_address(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    first == 100 && second & 0b1100_0000 == 64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miette::{Result, miette};
+
+    type TestResult = Result<()>;
+
+    const HOME: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 56);
+    const SHARED: Ipv4Addr = Ipv4Addr::new(100, 72, 3, 4);
+    const WAN: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+    const OTHER: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 9);
+
+    #[test]
+    fn only_100_64_0_0_10_is_shared() -> TestResult {
+        for (ip, expected) in [
+            (Ipv4Addr::new(100, 63, 255, 255), false),
+            (Ipv4Addr::new(100, 64, 0, 0), true),
+            (Ipv4Addr::new(100, 127, 255, 255), true),
+            (Ipv4Addr::new(100, 128, 0, 0), false),
+        ] {
+            if is_shared_address(ip) != expected {
+                return Err(miette!("Expected {ip} shared to be {expected}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn decides_from_the_strongest_evidence() -> TestResult {
+        let cases = [
+            (vec![SHARED], Some(WAN), Verdict::SharedLocalAddress(SHARED)),
+            (
+                vec![HOME],
+                Some(SHARED),
+                Verdict::RouterAddressNotPublic(SHARED),
+            ),
+            (vec![HOME], Some(OTHER), Verdict::RouterAddressNotWan(OTHER)),
+            (vec![HOME], Some(WAN), Verdict::RouterHasWanAddress),
+            (vec![HOME, WAN], None, Verdict::LocalHasWanAddress),
+            (vec![HOME], None, Verdict::Unknown),
+        ];
+
+        for (local, router, expected) in cases {
+            let evidence = Evidence {
+                local,
+                router,
+                wan: vec![WAN],
+            };
+
+            if evidence.verdict() != expected {
+                return Err(miette!(
+                    "Expected {expected:?} for {evidence:?}, got {:?}",
+                    evidence.verdict()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -1,10 +1,14 @@
 //! Command line interface for ip-address
 
+use crate::scope::Scope;
+use crate::{Bootstrap, Transport};
 use clap::Parser;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
 /// Work out what your IP Address is
 #[allow(clippy::struct_excessive_bools)]
-#[derive(Parser, Debug, Copy, Clone)]
+#[derive(Parser, Debug, Clone, Default)]
 #[clap(author, version, about, long_about = None)]
 pub struct Args {
     /// Only print IP addresses local to this machine
@@ -22,4 +26,250 @@ pub struct Args {
     /// Print the reverse DNS entries for the IP addresses
     #[clap(short = 'r', long = "reverse")]
     pub reverse: bool,
+    /// Print local addresses in CIDR notation, as each address with its prefix or the network it's on
+    #[clap(
+        long = "cidr",
+        value_name = "FORM",
+        value_enum,
+        num_args = 0..=1,
+        default_missing_value = "address",
+        conflicts_with_all = ["reverse", "dns_leak", "per_interface", "nat_type", "cgnat"]
+    )]
+    pub cidr: Option<Cidr>,
+    /// Print only the addresses, without the interface names local addresses are labelled with
+    #[clap(
+        long = "bare",
+        conflicts_with_all = ["reverse", "dns_leak", "per_interface", "nat_type", "cgnat"]
+    )]
+    pub bare: bool,
+    /// Print the address the system's DNS resolver queries from, next to the WAN address
+    #[clap(long = "dns-leak", conflicts_with = "only_local")]
+    pub dns_leak: bool,
+    /// Print the WAN address each local address egresses as, by sending lookups from each
+    #[clap(
+        long = "per-interface",
+        conflicts_with_all = ["only_local", "dns_leak", "source_addresses", "interface"]
+    )]
+    pub per_interface: bool,
+    /// Classify the NAT's mapping, filtering and hairpinning behaviour using STUN (RFC 5780)
+    #[clap(
+        long = "nat-type",
+        conflicts_with_all = ["only_local", "dns_leak", "per_interface", "proxy"]
+    )]
+    pub nat_type: bool,
+    /// Tell whether a carrier-grade NAT sits beyond the router, from the local, router and WAN addresses
+    #[clap(
+        long = "cgnat",
+        conflicts_with_all = ["only_local", "only_6", "dns_leak", "per_interface", "nat_type", "proxy"]
+    )]
+    pub cgnat: bool,
+    /// Only list local addresses on these interfaces, by name or glob such as eth* (defaults to all of them)
+    #[clap(
+        long = "only-interface",
+        value_name = "INTERFACE",
+        env = "WHATISMYIP_ONLY_INTERFACE",
+        value_delimiter = ','
+    )]
+    pub only_interfaces: Vec<String>,
+    /// Leave out local addresses on these interfaces, by name or glob such as docker*,veth*
+    #[clap(
+        long = "exclude-interface",
+        value_name = "INTERFACE",
+        env = "WHATISMYIP_EXCLUDE_INTERFACE",
+        value_delimiter = ','
+    )]
+    pub exclude_interfaces: Vec<String>,
+    /// Leave out temporary IPv6 privacy addresses (Linux only)
+    #[clap(
+        long = "no-temporary",
+        conflicts_with_all = ["only_wan", "dns_leak"]
+    )]
+    pub no_temporary: bool,
+    /// Leave out IPv6 addresses that are temporary, deprecated or tentative (Linux only)
+    #[clap(
+        long = "only-stable",
+        conflicts_with_all = ["only_wan", "dns_leak"]
+    )]
+    pub only_stable: bool,
+    /// Only print local and WAN addresses in these scopes, such as global,private (defaults to all of them)
+    #[clap(
+        long = "scope",
+        value_name = "SCOPE",
+        env = "WHATISMYIP_SCOPE",
+        value_enum,
+        value_delimiter = ','
+    )]
+    pub scopes: Vec<Scope>,
+    /// WAN providers to ask, in order of preference (defaults to google,opendns,cloudflare)
+    #[clap(
+        short = 'p',
+        long = "provider",
+        value_name = "PROVIDER",
+        value_delimiter = ','
+    )]
+    pub providers: Vec<String>,
+    /// URL of the echo service used by the http provider
+    #[clap(long = "http-url", value_name = "URL", env = "WHATISMYIP_HTTP_URL")]
+    pub http_url: Option<String>,
+    /// Proxy to find the WAN address through, as socks5://, socks5h:// or http:// (only the http provider can use it)
+    #[clap(long = "proxy", value_name = "URL", env = "WHATISMYIP_PROXY")]
+    pub proxy: Option<String>,
+    /// STUN servers used by the stun provider, as host:port
+    #[clap(
+        long = "stun-server",
+        value_name = "SERVER",
+        env = "WHATISMYIP_STUN_SERVER",
+        value_delimiter = ','
+    )]
+    pub stun_servers: Vec<String>,
+    /// Gateway the router provider asks over NAT-PMP and PCP, as an IP or IP:port (defaults to the default route's gateway)
+    #[clap(
+        long = "router",
+        value_name = "GATEWAY",
+        env = "WHATISMYIP_ROUTER",
+        value_parser = parse_gateway
+    )]
+    pub router: Option<SocketAddr>,
+    /// Nameservers the google provider sends its TXT query to, as hostnames or IPs
+    #[clap(
+        long = "wan-nameserver",
+        value_name = "NAMESERVER",
+        env = "WHATISMYIP_WAN_NAMESERVER",
+        value_delimiter = ','
+    )]
+    pub wan_nameservers: Vec<String>,
+    /// TXT record the google provider looks up to find the WAN address
+    #[clap(
+        long = "wan-record",
+        value_name = "RECORD",
+        env = "WHATISMYIP_WAN_RECORD"
+    )]
+    pub wan_record: Option<String>,
+    /// Local addresses to send WAN lookups from, at most one IPv4 and one IPv6
+    #[clap(
+        long = "source-address",
+        value_name = "IP",
+        env = "WHATISMYIP_SOURCE_ADDRESS",
+        value_delimiter = ',',
+        conflicts_with = "interface"
+    )]
+    pub source_addresses: Vec<IpAddr>,
+    /// Network interface to send WAN lookups from, such as eth1
+    #[clap(
+        long = "interface",
+        value_name = "INTERFACE",
+        env = "WHATISMYIP_INTERFACE"
+    )]
+    pub interface: Option<String>,
+    /// Protocol used for DNS queries, for WAN and reverse lookups
+    #[clap(
+        long = "transport",
+        value_name = "TRANSPORT",
+        env = "WHATISMYIP_TRANSPORT",
+        value_enum,
+        default_value_t
+    )]
+    pub transport: Transport,
+    /// How nameserver hostnames are looked up: with the system resolver, falling back to built-in addresses if it fails, or with the built-in addresses only
+    #[clap(
+        long = "bootstrap",
+        value_name = "MODE",
+        env = "WHATISMYIP_BOOTSTRAP",
+        value_enum,
+        default_value_t
+    )]
+    pub bootstrap: Bootstrap,
+    /// Seconds to wait for each nameserver before also trying the next (defaults to trying them all at once)
+    #[clap(
+        long = "stagger",
+        value_name = "SECONDS",
+        env = "WHATISMYIP_STAGGER",
+        value_parser = parse_seconds
+    )]
+    pub stagger: Option<Duration>,
+    /// Ask every WAN provider at once and only print an address enough of them agree on
+    #[clap(long = "consensus")]
+    pub consensus: bool,
+    /// How many WAN providers must agree in consensus mode (defaults to a majority)
+    #[clap(long = "quorum", value_name = "COUNT", requires = "consensus")]
+    pub quorum: Option<usize>,
+    /// Give up on finding the WAN address after this many seconds (defaults to 10)
+    #[clap(
+        long = "timeout",
+        value_name = "SECONDS",
+        env = "WHATISMYIP_TIMEOUT",
+        value_parser = parse_seconds
+    )]
+    pub timeout: Option<Duration>,
+    /// How many times to try each WAN provider
+    #[clap(
+        long = "attempts",
+        value_name = "COUNT",
+        env = "WHATISMYIP_ATTEMPTS",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub attempts: Option<u32>,
+    /// How many seconds each attempt at a WAN provider may take
+    #[clap(
+        long = "attempt-timeout",
+        value_name = "SECONDS",
+        env = "WHATISMYIP_ATTEMPT_TIMEOUT",
+        value_parser = parse_seconds
+    )]
+    pub attempt_timeout: Option<Duration>,
+    /// Seconds to wait before the first retry, doubling with each retry after
+    #[clap(
+        long = "backoff",
+        value_name = "SECONDS",
+        env = "WHATISMYIP_BACKOFF",
+        value_parser = parse_seconds
+    )]
+    pub backoff: Option<Duration>,
+}
+
+/// How local addresses are printed in CIDR notation
+#[derive(Copy, Debug, Clone, PartialEq, Eq)]
+pub enum Cidr {
+    /// Each address with its prefix length, such as `192.168.1.56/24`
+    Address,
+    /// The network each address is on, such as `192.168.1.0/24`, along with
+    /// its broadcast address
+    Network,
+}
+
+// Implemented by hand so that the variant docs don't switch the command line
+// help over to its long layout
+impl clap::ValueEnum for Cidr {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Address, Self::Network]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(match self {
+            Self::Address => "address",
+            Self::Network => "network",
+        }))
+    }
+}
+
+/// Parse a gateway address, using the NAT-PMP port when none is given
+fn parse_gateway(gateway: &str) -> Result<SocketAddr, String> {
+    gateway
+        .parse()
+        .or_else(|_| {
+            gateway
+                .trim_matches(['[', ']'])
+                .parse()
+                .map(|ip| SocketAddr::new(ip, crate::router::PORT))
+        })
+        .map_err(|_| format!("'{gateway}' is not an IP address or IP:port"))
+}
+
+/// Parse a number of seconds, which may be fractional, into a duration
+fn parse_seconds(seconds: &str) -> Result<Duration, String> {
+    let seconds: f64 = seconds
+        .parse()
+        .map_err(|_| format!("'{seconds}' is not a number of seconds"))?;
+
+    Duration::try_from_secs_f64(seconds).map_err(|e| e.to_string())
 }
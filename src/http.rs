@@ -0,0 +1,541 @@
+//! A minimal HTTP client for IP echo services
+//!
+//! Services like ipify and icanhazip reply to a plain `GET` with the address
+//! the request came from, either as bare text or as a small JSON document.
+//! This module implements just enough HTTP/1.1 to make that request over
+//! plain TCP or TLS, and to pull the address back out of the reply. It can
+//! also `POST` the SOAP requests `UPnP` gateways expect.
+//!
+//! ## Supported responses
+//!
+//! - Plain text bodies containing only an address, e.g. `203.0.113.7`
+//! - JSON objects with an address in one of their fields, e.g. `{"ip":"203.0.113.7"}`
+
+use crate::IpVersion::{Ipv4, Ipv6};
+use crate::proxy::Proxy;
+use crate::retry::IntoTransient;
+use crate::{IpVersion, SourceAddresses};
+use miette::{IntoDiagnostic, Result, bail, miette};
+use std::fmt::{self, Display, Formatter, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+/// The largest response we are willing to read
+const MAX_RESPONSE_LEN: u64 = 64 * 1024;
+
+/// An `http://` or `https://` URL, split into the parts needed to make a request
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Url {
+    /// Whether to use TLS
+    pub https: bool,
+    /// The host name or IP address, without brackets
+    pub host: String,
+    /// The TCP port to connect to
+    pub port: u16,
+    /// The path and query string to request
+    pub path: String,
+}
+
+impl FromStr for Url {
+    type Err = miette::Error;
+
+    fn from_str(url: &str) -> Result<Self> {
+        let (https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            bail!("Unsupported URL '{url}', expected http:// or https://");
+        };
+
+        let (authority, path) = rest
+            .find(['/', '?'])
+            .map_or((rest, "/"), |index| rest.split_at(index));
+        let path = if path.starts_with('?') {
+            format!("/{path}")
+        } else {
+            path.to_string()
+        };
+
+        // IPv6 literals are wrapped in brackets so the port can be told apart
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let (host, after) = bracketed
+                .split_once(']')
+                .ok_or_else(|| miette!("Unterminated IPv6 address in '{url}'"))?;
+            (host, after.strip_prefix(':'))
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            }
+        };
+
+        if host.is_empty() {
+            bail!("No host in URL '{url}'");
+        }
+
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| miette!("Invalid port '{port}' in '{url}'"))?,
+            None if https => 443,
+            None => 80,
+        };
+
+        Ok(Self {
+            https,
+            host: host.to_string(),
+            port,
+            path,
+        })
+    }
+}
+
+impl Display for Url {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let scheme = if self.https { "https" } else { "http" };
+        if self.host.contains(':') {
+            write!(f, "{scheme}://[{}]:{}{}", self.host, self.port, self.path)
+        } else {
+            write!(f, "{scheme}://{}:{}{}", self.host, self.port, self.path)
+        }
+    }
+}
+
+/// Fetch a URL and return the response body
+///
+/// The connection is made over the requested IP version, so an echo service
+/// that listens on both will report the matching address. No timeout is
+/// applied here; the `http` provider's requests are limited by its
+/// `retry::RetryPolicy`.
+///
+/// # Arguments
+///
+/// * `url` - The URL to request
+/// * `strategy` - The IP version to connect over
+/// * `source` - The local addresses to connect from
+/// * `proxy` - The proxy to connect through, if any
+///
+/// # Returns
+///
+/// The body of the response
+///
+/// # Errors
+///
+/// Returns an error if the host has no address of the right version, there
+/// is no source address of that version, the connection or proxy fails, or
+/// the server does not respond with a 2xx status
+pub async fn get(
+    url: &Url,
+    strategy: IpVersion,
+    source: SourceAddresses,
+    proxy: Option<&Proxy>,
+) -> Result<String> {
+    let request = Request {
+        method: "GET",
+        headers: &[("Accept", "text/plain, application/json")],
+        body: "",
+    };
+    fetch(url, strategy, source, proxy, &request).await
+}
+
+/// Send a `POST` request and return the response body
+///
+/// # Arguments
+///
+/// * `url` - The URL to post to
+/// * `strategy` - The IP version to connect over
+/// * `source` - The local addresses to connect from
+/// * `headers` - Extra headers to send, such as `Content-Type`
+/// * `body` - The body of the request
+///
+/// # Returns
+///
+/// The body of the response
+///
+/// # Errors
+///
+/// Returns an error if the host has no address of the right version, there
+/// is no source address of that version, the connection fails, or the server
+/// does not respond with a 2xx status
+pub async fn post(
+    url: &Url,
+    strategy: IpVersion,
+    source: SourceAddresses,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<String> {
+    let request = Request {
+        method: "POST",
+        headers,
+        body,
+    };
+    fetch(url, strategy, source, None, &request).await
+}
+
+/// The parts of a request that differ between methods
+struct Request<'a> {
+    method: &'a str,
+    headers: &'a [(&'a str, &'a str)],
+    body: &'a str,
+}
+
+/// Connect to the host in `url`, directly or through `proxy`, and send `request`
+async fn fetch(
+    url: &Url,
+    strategy: IpVersion,
+    source: SourceAddresses,
+    proxy: Option<&Proxy>,
+    request: &Request<'_>,
+) -> Result<String> {
+    let tcp = match proxy {
+        Some(proxy) => proxy.connect(&url.host, url.port, strategy, source).await?,
+        None => connect(&url.host, url.port, Some(strategy), source).await?,
+    };
+
+    if url.https {
+        let server_name = ServerName::try_from(url.host.clone()).into_diagnostic()?;
+        let tls = TlsConnector::from(tls_config()?)
+            .connect(server_name, tcp)
+            .await
+            .into_transient()?;
+        send(tls, url, request).await
+    } else {
+        send(tcp, url, request).await
+    }
+}
+
+/// Pull an IP address out of an echo service's response body
+///
+/// # Arguments
+///
+/// * `body` - The response body
+///
+/// # Returns
+///
+/// The address, if the body is a bare address or a JSON object with an
+/// address in one of its fields
+#[must_use]
+pub fn parse_ip(body: &str) -> Option<IpAddr> {
+    let body = body.trim();
+
+    if let Ok(ip) = IpAddr::from_str(body) {
+        return Some(ip);
+    }
+
+    let serde_json::Value::Object(fields) = serde_json::from_str(body).ok()? else {
+        return None;
+    };
+
+    // Prefer the conventional field name, but accept any field holding an address
+    fields
+        .get("ip")
+        .into_iter()
+        .chain(fields.values())
+        .filter_map(serde_json::Value::as_str)
+        .find_map(|value| IpAddr::from_str(value.trim()).ok())
+}
+
+/// The TLS configuration shared by every HTTPS request
+fn tls_config() -> Result<Arc<ClientConfig>> {
+    static CONFIG: LazyLock<Result<Arc<ClientConfig>, String>> = LazyLock::new(|| {
+        hickory_resolver::net::tls::client_config()
+            .map(Arc::new)
+            .map_err(|e| e.to_string())
+    });
+
+    CONFIG
+        .clone()
+        .map_err(|e| miette!("Failed to set up TLS: {e}"))
+}
+
+/// Open a TCP connection to the first reachable address of the right version
+///
+/// Any IP version is used when `strategy` is `None`, such as when connecting
+/// to a proxy rather than the echo service itself.
+pub(crate) async fn connect(
+    host: &str,
+    port: u16,
+    strategy: Option<IpVersion>,
+    source: SourceAddresses,
+) -> Result<TcpStream> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .into_transient()?
+        .filter(|addr| match strategy {
+            Some(Ipv4) => addr.is_ipv4(),
+            Some(Ipv6) => addr.is_ipv6(),
+            None => true,
+        })
+        .collect();
+
+    let mut last_error = strategy.map_or_else(
+        || miette!("{host} has no address"),
+        |strategy| miette!("{host} has no {strategy:?} address"),
+    );
+    for addr in addrs {
+        match connect_from(addr, source).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Open a TCP connection to `addr` from the source address of its IP version
+async fn connect_from(addr: SocketAddr, source: SourceAddresses) -> Result<TcpStream> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()
+    } else {
+        TcpSocket::new_v6()
+    }
+    .into_transient()?;
+    if let Some(local) = source.bind_addr(addr.ip())? {
+        socket.bind(local).into_transient()?;
+    }
+
+    socket
+        .connect(addr)
+        .await
+        .map_err(|e| miette!("Failed to connect to {addr}: {e}"))
+}
+
+/// Send a request over an open connection and read back the body
+async fn send<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    url: &Url,
+    request: &Request<'_>,
+) -> Result<String> {
+    let host = if url.host.contains(':') {
+        format!("[{}]", url.host)
+    } else {
+        url.host.clone()
+    };
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {host}\r\nUser-Agent: {}/{}\r\nConnection: close\r\n",
+        request.method,
+        url.path,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+    );
+    for (name, value) in request.headers {
+        let _ = write!(head, "{name}: {value}\r\n");
+    }
+    if !request.body.is_empty() {
+        let _ = write!(head, "Content-Length: {}\r\n", request.body.len());
+    }
+    head.push_str("\r\n");
+
+    stream.write_all(head.as_bytes()).await.into_transient()?;
+    stream
+        .write_all(request.body.as_bytes())
+        .await
+        .into_transient()?;
+
+    let mut response = Vec::new();
+    match (&mut stream)
+        .take(MAX_RESPONSE_LEN)
+        .read_to_end(&mut response)
+        .await
+    {
+        Ok(_) => {}
+        // Plenty of servers close the connection without a TLS close_notify
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && !response.is_empty() => {}
+        Err(e) => return Err(e).into_transient(),
+    }
+
+    parse_response(&response)
+}
+
+/// Check the status of a raw HTTP/1.x response and decode its body
+fn parse_response(response: &[u8]) -> Result<String> {
+    let response = String::from_utf8_lossy(response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| miette!("Incomplete HTTP response"))?;
+    let mut lines = head.lines();
+
+    let status = lines.next().unwrap_or_default();
+    let code = status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| miette!("Malformed HTTP status line '{status}'"))?;
+    if !(200..300).contains(&code) {
+        bail!("HTTP request failed: {status}");
+    }
+
+    let chunked = lines.any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.trim().eq_ignore_ascii_case("transfer-encoding")
+                && value.to_ascii_lowercase().contains("chunked")
+        })
+    });
+
+    if chunked {
+        decode_chunked(body)
+    } else {
+        Ok(body.to_string())
+    }
+}
+
+/// Reassemble a body sent with chunked transfer encoding
+fn decode_chunked(mut body: &str) -> Result<String> {
+    let mut decoded = String::with_capacity(body.len());
+
+    loop {
+        let (size, rest) = body
+            .split_once("\r\n")
+            .ok_or_else(|| miette!("Truncated chunked HTTP body"))?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|_| miette!("Invalid chunk size '{size}' in HTTP body"))?;
+
+        if size == 0 {
+            return Ok(decoded);
+        }
+
+        let chunk = rest
+            .get(..size)
+            .ok_or_else(|| miette!("Truncated chunked HTTP body"))?;
+        decoded.push_str(chunk);
+        body = rest[size..].trim_start_matches("\r\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+    use tokio::net::TcpListener;
+
+    type TestResult = Result<()>;
+
+    /// Serve a single request with a canned HTTP response
+    async fn stand_in(response: &'static str) -> Result<Url> {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .into_diagnostic()?;
+        let addr = listener.local_addr().into_diagnostic()?;
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await?;
+            let mut request = [0; 1024];
+            let _ = socket.read(&mut request).await?;
+            socket.write_all(response.as_bytes()).await
+        });
+
+        format!("http://{addr}/").parse()
+    }
+
+    #[test]
+    fn parses_urls() -> TestResult {
+        let cases = [
+            ("https://icanhazip.com", true, "icanhazip.com", 443, "/"),
+            (
+                "http://localhost:8080/ip?format=json",
+                false,
+                "localhost",
+                8080,
+                "/ip?format=json",
+            ),
+            ("http://[::1]:81/", false, "::1", 81, "/"),
+            ("https://example.com?x=1", true, "example.com", 443, "/?x=1"),
+        ];
+
+        for (input, https, host, port, path) in cases {
+            let url: Url = input.parse()?;
+            let expected = Url {
+                https,
+                host: host.to_string(),
+                port,
+                path: path.to_string(),
+            };
+
+            if url != expected {
+                return Err(miette!("Expected {expected:?} for '{input}', got {url:?}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_other_schemes() -> TestResult {
+        if "ftp://example.com/".parse::<Url>().is_ok() {
+            return Err(miette!("Expected an ftp:// URL to be rejected"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_plain_and_json_bodies() -> TestResult {
+        let expected = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7));
+        let bodies = [
+            "203.0.113.7\n",
+            r#"{"ip":"203.0.113.7"}"#,
+            r#"{"country":"NL","address":"203.0.113.7"}"#,
+        ];
+
+        for body in bodies {
+            if parse_ip(body) != Some(expected) {
+                return Err(miette!("Expected {expected} from '{body}'"));
+            }
+        }
+
+        if let Some(ip) = parse_ip("<html>nope</html>") {
+            return Err(miette!("Expected no address from HTML, got {ip}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reads_the_body_from_a_server() -> TestResult {
+        let url = stand_in("HTTP/1.1 200 OK\r\nContent-Length: 12\r\n\r\n203.0.113.7\n").await?;
+        let body = get(&url, Ipv4, SourceAddresses::default(), None).await?;
+
+        if body != "203.0.113.7\n" {
+            return Err(miette!("Expected the address, got '{body}'"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn decodes_chunked_bodies() -> TestResult {
+        let url = stand_in(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n{\"ip\"\r\nf\r\n:\"203.0.113.7\"}\r\n0\r\n\r\n",
+        )
+        .await?;
+        let body = get(&url, Ipv4, SourceAddresses::default(), None).await?;
+
+        if body != r#"{"ip":"203.0.113.7"}"# {
+            return Err(miette!("Expected the JSON document, got '{body}'"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fails_on_error_statuses() -> TestResult {
+        let url = stand_in("HTTP/1.1 503 Service Unavailable\r\n\r\n").await?;
+
+        if get(&url, Ipv4, SourceAddresses::default(), None)
+            .await
+            .is_ok()
+        {
+            return Err(miette!("Expected a 503 to be an error"));
+        }
+
+        Ok(())
+    }
+}
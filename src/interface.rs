@@ -0,0 +1,493 @@
+//! Choosing which network interfaces local addresses are listed from
+//!
+//! Hosts running containers or VPNs can have dozens of interfaces, such as
+//! `docker0`, `veth1a2b3c` or `br-5f0e`, whose addresses are rarely the ones
+//! wanted. An `InterfaceFilter` picks interfaces by name, with `*` matching
+//! any run of characters and `?` matching any single character. It can also
+//! leave out IPv6 addresses by their flags, such as temporary privacy
+//! addresses that change every day or so.
+//!
+//! On Linux, addresses are listed over netlink, which also reports the
+//! length of each address's on-link prefix, and the flags the kernel keeps
+//! for IPv6 addresses. Elsewhere the prefix and flags are unknown.
+
+use miette::Result;
+#[cfg(target_os = "linux")]
+use miette::{IntoDiagnostic, miette};
+use std::net::IpAddr;
+
+/// An address on one of this machine's network interfaces
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    /// The name of the interface, such as `wlan0`
+    pub name: String,
+    /// The address
+    pub ip: IpAddr,
+    /// How many leading bits of the address are on-link, where the platform
+    /// reports it
+    pub prefix_len: Option<u8>,
+    /// What the kernel says about an IPv6 address, such as whether it's a
+    /// temporary privacy address, where the platform reports it
+    pub flags: Option<AddressFlags>,
+}
+
+/// The flags Linux keeps for an IPv6 address (`IFA_F_*`)
+///
+/// The same bits mean other things for IPv4 addresses, so they are only
+/// read for IPv6 addresses.
+#[derive(Copy, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct AddressFlags(u32);
+
+impl AddressFlags {
+    /// A temporary address, from privacy extensions (RFC 8981)
+    pub const TEMPORARY: u32 = 0x01;
+    /// An address whose preferred lifetime has run out, so new connections
+    /// avoid it
+    pub const DEPRECATED: u32 = 0x20;
+    /// An address still going through duplicate address detection, which
+    /// can't be used yet
+    pub const TENTATIVE: u32 = 0x40;
+    /// An address that doesn't expire, such as a link-local or manually
+    /// configured one
+    pub const PERMANENT: u32 = 0x80;
+    /// An address the kernel creates temporary addresses from
+    pub const MANAGED: u32 = 0x100;
+    /// A stable privacy address, derived from a secret rather than the
+    /// hardware address (RFC 7217)
+    pub const STABLE_PRIVACY: u32 = 0x800;
+
+    /// Create the flags from the kernel's bits
+    #[must_use]
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Get the kernel's bits
+    #[must_use]
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Whether the address is a temporary privacy address
+    #[must_use]
+    pub const fn is_temporary(self) -> bool {
+        self.0 & Self::TEMPORARY != 0
+    }
+
+    /// Whether the address's preferred lifetime has run out
+    #[must_use]
+    pub const fn is_deprecated(self) -> bool {
+        self.0 & Self::DEPRECATED != 0
+    }
+
+    /// Whether the address is still going through duplicate address detection
+    #[must_use]
+    pub const fn is_tentative(self) -> bool {
+        self.0 & Self::TENTATIVE != 0
+    }
+
+    /// Whether the kernel creates temporary addresses from the address
+    #[must_use]
+    pub const fn is_managed(self) -> bool {
+        self.0 & Self::MANAGED != 0
+    }
+
+    /// Whether the address is a stable privacy address (RFC 7217)
+    #[must_use]
+    pub const fn is_stable_privacy(self) -> bool {
+        self.0 & Self::STABLE_PRIVACY != 0
+    }
+
+    /// Whether the address was learnt, such as through SLAAC or `DHCPv6`, and
+    /// expires unless renewed
+    #[must_use]
+    pub const fn is_dynamic(self) -> bool {
+        self.0 & Self::PERMANENT == 0
+    }
+
+    /// Whether the address is one to record and hand out: not temporary, and
+    /// neither deprecated nor tentative
+    #[must_use]
+    pub const fn is_stable(self) -> bool {
+        !(self.is_temporary() || self.is_deprecated() || self.is_tentative())
+    }
+}
+
+/// Which network interfaces, and which of their addresses, to list local
+/// addresses from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterfaceFilter {
+    /// Names or globs of the interfaces to include; empty includes them all
+    pub include: Vec<String>,
+    /// Names or globs of the interfaces to leave out, even if included
+    pub exclude: Vec<String>,
+    /// Leave out temporary privacy addresses
+    pub no_temporary: bool,
+    /// Leave out addresses that aren't stable, as for `AddressFlags::is_stable`
+    pub only_stable: bool,
+}
+
+impl InterfaceFilter {
+    /// Whether addresses on the interface called `name` should be listed
+    #[must_use]
+    pub fn matches(&self, name: &str) -> bool {
+        let included = self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|pattern| glob_matches(pattern, name));
+
+        included
+            && !self
+                .exclude
+                .iter()
+                .any(|pattern| glob_matches(pattern, name))
+    }
+
+    /// Whether an address with these flags should be listed
+    ///
+    /// Addresses without flags, such as IPv4 addresses or those on platforms
+    /// that don't report them, are always listed.
+    #[must_use]
+    pub fn keeps_flags(&self, flags: Option<AddressFlags>) -> bool {
+        flags.is_none_or(|flags| {
+            !(self.no_temporary && flags.is_temporary() || self.only_stable && !flags.is_stable())
+        })
+    }
+
+    /// Whether an address should be listed, going by both its interface's
+    /// name and its flags
+    #[must_use]
+    pub fn keeps(&self, address: &InterfaceAddress) -> bool {
+        self.matches(&address.name) && self.keeps_flags(address.flags)
+    }
+}
+
+/// List the addresses on this machine's network interfaces
+///
+/// # Returns
+///
+/// Each address along with its interface, in the order the operating system
+/// lists them
+///
+/// # Errors
+///
+/// Returns an error if the network interfaces cannot be listed
+pub fn list_addresses() -> Result<Vec<InterfaceAddress>> {
+    #[cfg(target_os = "linux")]
+    {
+        list_addresses_over_netlink()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        use miette::IntoDiagnostic;
+
+        Ok(local_ip_address::list_afinet_netifas()
+            .into_diagnostic()?
+            .into_iter()
+            .map(|(name, ip)| InterfaceAddress {
+                name,
+                ip,
+                prefix_len: None,
+                flags: None,
+            })
+            .collect())
+    }
+}
+
+/// List the addresses on this machine's network interfaces with an
+/// `RTM_GETADDR` dump, which includes their prefix lengths and flags
+///
+/// # Errors
+///
+/// Returns an error if the netlink socket can't be opened or a reply can't
+/// be read
+#[cfg(target_os = "linux")]
+fn list_addresses_over_netlink() -> Result<Vec<InterfaceAddress>> {
+    use neli::attr::Attribute;
+    use neli::consts::nl::NlmF;
+    use neli::consts::rtnl::{Ifa, Ifla, RtAddrFamily, RtScope, Rtm};
+    use neli::consts::socket::NlFamily;
+    use neli::nl::{NlPayload, Nlmsghdr};
+    use neli::router::synchronous::NlRouter;
+    use neli::rtnl::{Ifaddrmsg, IfaddrmsgBuilder, Ifinfomsg, IfinfomsgBuilder};
+    use neli::utils::Groups;
+    use std::collections::HashMap;
+
+    let (socket, _) = NlRouter::connect(NlFamily::Route, None, Groups::empty())
+        .map_err(|e| miette!("Failed to open a netlink socket: {e}"))?;
+
+    // Only IPv4 addresses carry a label, so look the interface names up too
+    let request = IfinfomsgBuilder::default()
+        .ifi_family(RtAddrFamily::Unspecified)
+        .build()
+        .into_diagnostic()?;
+    let replies = socket
+        .send(
+            Rtm::Getlink,
+            NlmF::REQUEST | NlmF::DUMP,
+            NlPayload::Payload(request),
+        )
+        .map_err(|e| miette!("Failed to ask for the network interfaces: {e}"))?;
+
+    let mut names = HashMap::new();
+    for reply in replies {
+        let reply: Nlmsghdr<Rtm, Ifinfomsg> =
+            reply.map_err(|e| miette!("Failed to read the network interfaces: {e}"))?;
+        let Some(link) = reply
+            .get_payload()
+            .filter(|_| *reply.nl_type() == Rtm::Newlink)
+        else {
+            continue;
+        };
+        if let Some(name) = link
+            .rtattrs()
+            .iter()
+            .find(|attr| *attr.rta_type() == Ifla::Ifname)
+        {
+            names.insert(*link.ifi_index(), parse_name(name.payload().as_ref()));
+        }
+    }
+
+    let request = IfaddrmsgBuilder::default()
+        .ifa_family(RtAddrFamily::Unspecified)
+        .ifa_prefixlen(0)
+        .ifa_scope(RtScope::Universe)
+        .ifa_index(0)
+        .build()
+        .into_diagnostic()?;
+    let replies = socket
+        .send(
+            Rtm::Getaddr,
+            NlmF::REQUEST | NlmF::DUMP,
+            NlPayload::Payload(request),
+        )
+        .map_err(|e| miette!("Failed to ask for the interface addresses: {e}"))?;
+
+    let mut addresses = Vec::new();
+    for reply in replies {
+        let reply: Nlmsghdr<Rtm, Ifaddrmsg> =
+            reply.map_err(|e| miette!("Failed to read the interface addresses: {e}"))?;
+        let Some(address) = reply
+            .get_payload()
+            .filter(|_| *reply.nl_type() == Rtm::Newaddr)
+        else {
+            continue;
+        };
+
+        let mut ip = None;
+        let mut label = None;
+        // Flags that don't fit in the header's byte only come as an attribute
+        let mut flags = u32::from(address.ifa_flags().bits());
+        for attr in address.rtattrs().iter() {
+            let payload = attr.payload().as_ref();
+            match attr.rta_type() {
+                Ifa::Label => label = Some(parse_name(payload)),
+                Ifa::Flags => {
+                    if let Ok(bits) = <[u8; 4]>::try_from(payload) {
+                        flags = u32::from_ne_bytes(bits);
+                    }
+                }
+                // Point-to-point links put the peer in IFA_ADDRESS, and our own
+                // address in IFA_LOCAL
+                Ifa::Local => ip = parse_ip(payload),
+                Ifa::Address if ip.is_none() => ip = parse_ip(payload),
+                _ => {}
+            }
+        }
+
+        let index = i32::try_from(*address.ifa_index()).ok();
+        let name = label.or_else(|| names.get(&index?).cloned());
+        if let (Some(ip), Some(name)) = (ip, name) {
+            addresses.push(InterfaceAddress {
+                name,
+                ip,
+                prefix_len: Some(*address.ifa_prefixlen()),
+                flags: ip.is_ipv6().then_some(AddressFlags::from_bits(flags)),
+            });
+        }
+    }
+
+    Ok(addresses)
+}
+
+/// Read a NUL-terminated interface name from a netlink attribute
+#[cfg(target_os = "linux")]
+fn parse_name(payload: &[u8]) -> String {
+    let name = payload.split(|byte| *byte == 0).next().unwrap_or_default();
+    String::from_utf8_lossy(name).into_owned()
+}
+
+/// Read an IPv4 or IPv6 address from a netlink attribute
+#[cfg(target_os = "linux")]
+fn parse_ip(payload: &[u8]) -> Option<IpAddr> {
+    <[u8; 4]>::try_from(payload)
+        .map(IpAddr::from)
+        .or_else(|_| <[u8; 16]>::try_from(payload).map(IpAddr::from))
+        .ok()
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any run of characters
+/// and `?` matches any single character
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` was, and how much of the name it has taken so far
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // Let the last `*` take one more character and try again
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miette::{Result, miette};
+
+    type TestResult = Result<()>;
+
+    #[test]
+    fn globs_match_whole_names() -> TestResult {
+        for (pattern, name, expected) in [
+            ("eth0", "eth0", true),
+            ("eth0", "eth01", false),
+            ("docker*", "docker0", true),
+            ("docker*", "docker", true),
+            ("veth*", "eth0", false),
+            ("br-*", "br-5f0e1c", true),
+            ("wl?0", "wlp0", true),
+            ("wl?0", "wl0", false),
+            ("*0", "tun0", true),
+            ("*n*0", "tun10", true),
+            ("", "", true),
+        ] {
+            if glob_matches(pattern, name) != expected {
+                return Err(miette!(
+                    "Expected '{pattern}' matching '{name}' to be {expected}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reads_ipv6_address_flags() -> TestResult {
+        let cases = [
+            // A SLAAC address privacy extensions make temporaries from
+            (AddressFlags::MANAGED, true, false, true),
+            (AddressFlags::TEMPORARY, false, true, true),
+            (
+                AddressFlags::TEMPORARY | AddressFlags::DEPRECATED,
+                false,
+                true,
+                true,
+            ),
+            (AddressFlags::PERMANENT, true, false, false),
+            (
+                AddressFlags::TENTATIVE | AddressFlags::PERMANENT,
+                false,
+                false,
+                false,
+            ),
+            (0, true, false, true),
+        ];
+
+        for (bits, stable, temporary, dynamic) in cases {
+            let flags = AddressFlags::from_bits(bits);
+            if (flags.is_stable(), flags.is_temporary(), flags.is_dynamic())
+                != (stable, temporary, dynamic)
+            {
+                return Err(miette!(
+                    "Expected {bits:#x} to be stable: {stable}, temporary: {temporary}, dynamic: {dynamic}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn exclusions_win_over_inclusions() -> TestResult {
+        let filter = InterfaceFilter {
+            include: vec!["eth*".to_string(), "wlan0".to_string()],
+            exclude: vec!["eth1".to_string()],
+            ..InterfaceFilter::default()
+        };
+
+        for (name, expected) in [
+            ("eth0", true),
+            ("eth1", false),
+            ("wlan0", true),
+            ("docker0", false),
+        ] {
+            if filter.matches(name) != expected {
+                return Err(miette!("Expected {name} to be listed: {expected}"));
+            }
+        }
+
+        if !InterfaceFilter::default().matches("docker0") {
+            return Err(miette!("Expected the default filter to list everything"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn leaves_out_addresses_by_their_flags() -> TestResult {
+        let flags = [
+            None,
+            Some(AddressFlags::MANAGED),
+            Some(AddressFlags::TEMPORARY),
+            Some(AddressFlags::TEMPORARY | AddressFlags::DEPRECATED),
+            Some(AddressFlags::DEPRECATED),
+        ];
+
+        for (no_temporary, only_stable, expected) in [
+            (false, false, vec![0, 1, 2, 3, 4]),
+            (true, false, vec![0, 1, 4]),
+            (false, true, vec![0, 1]),
+        ] {
+            let filter = InterfaceFilter {
+                no_temporary,
+                only_stable,
+                ..InterfaceFilter::default()
+            };
+            let actual: Vec<_> = (0..flags.len())
+                .filter(|&index| filter.keeps_flags(flags[index].map(AddressFlags::from_bits)))
+                .collect();
+
+            if actual != expected {
+                return Err(miette!(
+                    "Expected no_temporary: {no_temporary}, only_stable: {only_stable} to keep {expected:?}, got {actual:?}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
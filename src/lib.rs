@@ -9,9 +9,32 @@
 //!
 //! - Local IP discovery through network interface enumeration
 //! - External IP discovery using DNS queries to Google's nameservers
+//! - External IP discovery using HTTP(S) echo services such as icanhazip
+//! - UDP-mapped address discovery using STUN Binding Requests
+//! - NAT behaviour discovery and classification using STUN (RFC 5780)
+//! - Asking the local router for its external address over NAT-PMP, PCP or `UPnP`
+//! - Discovering the address seen through a SOCKS5 or HTTP proxy
+//! - Pluggable WAN providers that can be registered, ordered and selected
 //! - Reverse DNS resolution for IP addresses
+//! - DNS leak checks that find the egress address of the system's resolver
+//! - DNS queries over UDP, TCP, TLS or HTTPS
+//! - Configurable retries, backoff and timeouts for WAN lookups
+//! - Sending WAN lookups from a chosen source address or network interface
+//! - Finding the WAN address each local address egresses as
+//! - Detecting carrier-grade NAT between the router and the internet
 //! - Filtering by IP version (IPv4/IPv6)
+//! - Filtering local addresses by interface name or glob
+//! - Prefix lengths, networks and broadcast addresses of local addresses
+//! - IPv6 address flags, such as temporary or deprecated, on Linux
+//! - Classifying and filtering addresses by scope, such as private or global
 //! - Concurrent processing for efficient lookups
+//!
+//! ## Long-running programs
+//!
+//! DNS resolvers are cached between lookups, and nameserver addresses are
+//! cached until their TTL runs out. After the network changes, such as when
+//! switching Wi-Fi networks or connecting to a VPN, call `reset` and
+//! `provider::WanProviders::reset` so the next lookup uses the new network.
 
 #![warn(clippy::nursery)]
 #![deny(
@@ -32,24 +55,43 @@
 #![allow(clippy::multiple_crate_versions)]
 
 use crate::IpVersion::{Ipv4, Ipv6};
+use crate::interface::InterfaceFilter;
+use crate::retry::IntoTransient;
+use futures::stream::FuturesUnordered;
 use futures::{StreamExt, stream};
-use hickory_resolver::config::{LookupIpStrategy, NameServerConfig, ResolverConfig};
+use hickory_resolver::config::{
+    CLOUDFLARE, LookupIpStrategy, NameServerConfig, ProtocolConfig, ResolverConfig,
+};
 use hickory_resolver::net::runtime::TokioRuntimeProvider;
 use hickory_resolver::proto::rr::RData;
 use hickory_resolver::{Resolver, TokioResolver};
-use local_ip_address::list_afinet_netifas;
 use miette::{IntoDiagnostic, Result, miette};
-use std::collections::HashSet;
-use std::net::IpAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
-use tokio::sync::OnceCell;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
 
+pub mod cgnat;
 pub mod cli;
+pub mod http;
+pub mod interface;
 pub mod myip;
+pub mod nat;
+pub mod provider;
+pub mod proxy;
+pub mod retry;
+pub mod router;
+pub mod scope;
+pub mod stun;
 
 /// A collection of IP addresses
 pub type MyIps = Vec<myip::MyIp>;
 
+/// A collection of IP addresses, along with what is known about where each
+/// was found
+pub type Addresses = Vec<myip::Address>;
+
 /// Google's primary nameserver hostname
 pub const GOOGLE_NS1: &str = "ns1.google.com";
 /// Google's secondary nameserver hostname
@@ -61,6 +103,59 @@ pub const GOOGLE_NS4: &str = "ns4.google.com";
 /// Special Google DNS record that returns the client's IP address
 pub const MYADDR_RECORD: &str = "o-o.myaddr.l.google.com";
 
+/// Well-known anycast addresses of the nameservers the built-in providers use
+///
+/// These are used when the system resolver can't look the nameservers up,
+/// such as on hosts with a broken `/etc/resolv.conf`, or when asked for with
+/// `Bootstrap::Builtin`.
+pub const BUILTIN_ADDRESSES: [(&str, Ipv4Addr, Ipv6Addr); 8] = [
+    (
+        GOOGLE_NS1,
+        Ipv4Addr::new(216, 239, 32, 10),
+        Ipv6Addr::new(0x2001, 0x4860, 0x4802, 0x32, 0, 0, 0, 0xa),
+    ),
+    (
+        GOOGLE_NS2,
+        Ipv4Addr::new(216, 239, 34, 10),
+        Ipv6Addr::new(0x2001, 0x4860, 0x4802, 0x34, 0, 0, 0, 0xa),
+    ),
+    (
+        GOOGLE_NS3,
+        Ipv4Addr::new(216, 239, 36, 10),
+        Ipv6Addr::new(0x2001, 0x4860, 0x4802, 0x36, 0, 0, 0, 0xa),
+    ),
+    (
+        GOOGLE_NS4,
+        Ipv4Addr::new(216, 239, 38, 10),
+        Ipv6Addr::new(0x2001, 0x4860, 0x4802, 0x38, 0, 0, 0, 0xa),
+    ),
+    (
+        "resolver1.opendns.com",
+        Ipv4Addr::new(208, 67, 222, 222),
+        Ipv6Addr::new(0x2620, 0x119, 0x35, 0, 0, 0, 0, 0x35),
+    ),
+    (
+        "resolver2.opendns.com",
+        Ipv4Addr::new(208, 67, 220, 220),
+        Ipv6Addr::new(0x2620, 0x119, 0x53, 0, 0, 0, 0, 0x53),
+    ),
+    (
+        "resolver3.opendns.com",
+        Ipv4Addr::new(208, 67, 222, 220),
+        Ipv6Addr::new(0x2620, 0x0, 0xccc, 0, 0, 0, 0, 0x2),
+    ),
+    (
+        "resolver4.opendns.com",
+        Ipv4Addr::new(208, 67, 220, 222),
+        Ipv6Addr::new(0x2620, 0x0, 0xccd, 0, 0, 0, 0, 0x2),
+    ),
+];
+
+/// How long a built-in address is used before the system resolver is asked again
+const BUILTIN_TTL: Duration = Duration::from_mins(5);
+/// How long the system resolver is given before falling back to a built-in address
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(2);
+
 /// Represents the version of IP address to use
 #[derive(Copy, Debug, Clone)]
 pub enum IpVersion {
@@ -70,6 +165,348 @@ pub enum IpVersion {
     Ipv6,
 }
 
+/// The protocol used to send DNS queries
+#[derive(Copy, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum Transport {
+    /// Plain DNS over UDP, retrying over TCP when an answer is truncated
+    #[default]
+    Udp,
+    /// Plain DNS over TCP
+    Tcp,
+    /// DNS-over-TLS
+    Tls,
+    /// DNS-over-HTTPS
+    Https,
+}
+
+impl Transport {
+    /// The port nameservers listen on for this transport
+    #[must_use]
+    pub const fn default_port(self) -> u16 {
+        match self {
+            Self::Udp | Self::Tcp => 53,
+            Self::Tls => 853,
+            Self::Https => 443,
+        }
+    }
+
+    /// Describe how to reach a nameserver over this transport
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - The IP address and port of the nameserver
+    /// * `server_name` - The name the nameserver's TLS certificate is checked
+    ///   against; ignored for plain UDP and TCP
+    ///
+    /// # Returns
+    ///
+    /// The nameserver configuration to build a resolver with
+    #[must_use]
+    pub fn name_server(self, addr: SocketAddr, server_name: &str) -> NameServerConfig {
+        let mut name_server = match self {
+            Self::Udp => NameServerConfig::udp_and_tcp(addr.ip()),
+            Self::Tcp => NameServerConfig::tcp(addr.ip()),
+            Self::Tls => NameServerConfig::tls(addr.ip(), server_name.into()),
+            Self::Https => NameServerConfig::https(addr.ip(), server_name.into(), None),
+        };
+        for connection in &mut name_server.connections {
+            connection.port = addr.port();
+        }
+        name_server
+    }
+}
+
+/// How nameserver hostnames, such as `ns1.google.com`, are turned into addresses
+#[derive(Copy, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub enum Bootstrap {
+    /// Ask the system resolver, falling back to `BUILTIN_ADDRESSES` if it fails
+    #[default]
+    System,
+    /// Use `BUILTIN_ADDRESSES` for the nameservers it lists, without asking
+    /// the system resolver
+    Builtin,
+}
+
+impl clap::ValueEnum for Bootstrap {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::System, Self::Builtin]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(match self {
+            Self::System => "system",
+            Self::Builtin => "builtin",
+        }))
+    }
+}
+
+/// A nameserver whose built-in address was used because the system
+/// resolver failed to look it up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootstrapFallback {
+    /// The nameserver's hostname
+    pub host: String,
+    /// The built-in address used instead
+    pub ip: IpAddr,
+    /// Why the system resolver failed
+    pub reason: String,
+}
+
+// Implemented by hand so that the variant docs don't switch the command line
+// help over to its long layout
+impl clap::ValueEnum for Transport {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Udp, Self::Tcp, Self::Tls, Self::Https]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(match self {
+            Self::Udp => "udp",
+            Self::Tcp => "tcp",
+            Self::Tls => "tls",
+            Self::Https => "https",
+        }))
+    }
+}
+
+/// How DNS queries for WAN and reverse lookups are sent
+#[derive(Copy, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct DnsOptions {
+    /// The protocol used to talk to nameservers
+    pub transport: Transport,
+    /// How long to wait for one nameserver before also trying the next, when
+    /// choosing between several; if `None` they are all tried at once
+    pub stagger: Option<Duration>,
+    /// The local addresses WAN queries are sent from; queries through the
+    /// system resolver, such as reverse lookups, are left to the kernel
+    pub source: SourceAddresses,
+    /// How nameserver hostnames are turned into addresses
+    pub bootstrap: Bootstrap,
+}
+
+/// The local addresses WAN lookups are sent from
+///
+/// Normally the kernel picks the source address from the routing table. On a
+/// multi-homed host, setting one decides which uplink a lookup leaves over,
+/// so the public address of each uplink can be checked.
+#[derive(Copy, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SourceAddresses {
+    /// The address IPv4 lookups are sent from
+    pub ipv4: Option<Ipv4Addr>,
+    /// The address IPv6 lookups are sent from
+    pub ipv6: Option<Ipv6Addr>,
+}
+
+impl SourceAddresses {
+    /// Send lookups from specific addresses, at most one of each IP version
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if more than one address of an IP version is given
+    pub fn from_ips(ips: impl IntoIterator<Item = IpAddr>) -> Result<Self> {
+        let mut source = Self::default();
+
+        for ip in ips {
+            let duplicate = match ip {
+                IpAddr::V4(ip) => source.ipv4.replace(ip).is_some(),
+                IpAddr::V6(ip) => source.ipv6.replace(ip).is_some(),
+            };
+            if duplicate {
+                return Err(miette!(
+                    "Only one source address of each IP version can be used, but {ip} was given as well"
+                ));
+            }
+        }
+
+        Ok(source)
+    }
+
+    /// Send lookups from the addresses of a network interface
+    ///
+    /// The first IPv4 address, and the first IPv6 address that isn't
+    /// link-local, are used. Link-local addresses can't reach the internet.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name of the interface, such as `eth1`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the interfaces cannot be listed, or the interface
+    /// has no usable address
+    pub fn from_interface(name: &str) -> Result<Self> {
+        let addresses = interface::list_addresses()?;
+        let mut source = Self::default();
+
+        for address in addresses.into_iter().filter(|address| address.name == name) {
+            match address.ip {
+                IpAddr::V4(ip) => {
+                    source.ipv4.get_or_insert(ip);
+                }
+                IpAddr::V6(ip) if !ip.is_unicast_link_local() => {
+                    source.ipv6.get_or_insert(ip);
+                }
+                IpAddr::V6(_) => {}
+            }
+        }
+
+        if source == Self::default() {
+            return Err(miette!("Interface '{name}' has no address to send from"));
+        }
+
+        Ok(source)
+    }
+
+    /// The address to bind to before talking to `remote`
+    ///
+    /// # Returns
+    ///
+    /// The source address of the same IP version as `remote` with any port,
+    /// or `None` if no source addresses were set and the kernel should choose
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if source addresses were set, but none of the same IP
+    /// version as `remote`
+    pub fn bind_addr(self, remote: IpAddr) -> Result<Option<SocketAddr>> {
+        let ip = match remote {
+            IpAddr::V4(_) => self.ipv4.map(IpAddr::V4),
+            IpAddr::V6(_) => self.ipv6.map(IpAddr::V6),
+        };
+
+        match ip {
+            Some(ip) => Ok(Some(SocketAddr::new(ip, 0))),
+            None if self == Self::default() => Ok(None),
+            None => Err(miette!("No source address to send to {remote} from")),
+        }
+    }
+
+    /// The address to bind a socket to before talking to `remote`
+    ///
+    /// This is the same as `bind_addr`, but falls back to the unspecified
+    /// address of the right IP version when no source addresses were set.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if source addresses were set, but none of the same IP
+    /// version as `remote`
+    pub fn local_addr(self, remote: IpAddr) -> Result<SocketAddr> {
+        Ok(self.bind_addr(remote)?.unwrap_or_else(|| match remote {
+            IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        }))
+    }
+}
+
+/// A single shared provider for `find_wan_ip`, so its resolvers are cached between calls
+static GOOGLE: LazyLock<provider::Google> = LazyLock::new(provider::Google::default);
+
+/// Nameserver addresses, and when they expire, by hostname
+type AddressCache = LazyLock<Mutex<HashMap<String, (IpAddr, Instant)>>>;
+
+/// Cached IPv4 addresses of nameservers, used by `resolver_ip`
+static IPV4_CACHE: AddressCache = LazyLock::new(|| Mutex::new(HashMap::new()));
+/// Cached IPv6 addresses of nameservers, used by `resolver_ip`
+static IPV6_CACHE: AddressCache = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Nameservers that fell back to their built-in address, since the last reset
+static BOOTSTRAP_FALLBACKS: LazyLock<Mutex<Vec<BootstrapFallback>>> =
+    LazyLock::new(|| Mutex::new(Vec::new()));
+
+/// System resolvers, for each combination of options they were built with
+///
+/// There are only ever a handful of combinations, so a list is enough.
+static SYSTEM_RESOLVERS: LazyLock<Mutex<Vec<(LookupIpStrategy, DnsOptions, TokioResolver)>>> =
+    LazyLock::new(|| Mutex::new(Vec::new()));
+
+/// Forget every cached resolver and nameserver address
+///
+/// Resolvers are built once and then reused, so they carry on using the
+/// network they were built on. Call this after the network changes, such as
+/// when switching Wi-Fi networks or connecting to a VPN, so that the next
+/// lookup starts afresh. Providers you have built yourself are reset with
+/// `provider::WanProviders::reset`.
+pub fn reset() {
+    use provider::WanProvider;
+
+    for cache in [&IPV4_CACHE, &IPV6_CACHE] {
+        if let Ok(mut cache_guard) = cache.lock() {
+            cache_guard.clear();
+        }
+    }
+    if let Ok(mut resolvers) = SYSTEM_RESOLVERS.lock() {
+        resolvers.clear();
+    }
+    if let Ok(mut fallbacks) = BOOTSTRAP_FALLBACKS.lock() {
+        fallbacks.clear();
+    }
+    GOOGLE.reset();
+}
+
+/// The nameservers whose built-in address was used because the system
+/// resolver failed, since the program started or `reset` was last called
+///
+/// Each nameserver is listed once, with the most recent failure.
+#[must_use]
+pub fn bootstrap_fallbacks() -> Vec<BootstrapFallback> {
+    BOOTSTRAP_FALLBACKS
+        .lock()
+        .map(|fallbacks| fallbacks.clone())
+        .unwrap_or_default()
+}
+
+/// Look a nameserver up in `BUILTIN_ADDRESSES`
+fn builtin_address(host: &str, ip_strategy: LookupIpStrategy) -> Option<IpAddr> {
+    let host = host.trim_end_matches('.');
+    BUILTIN_ADDRESSES
+        .iter()
+        .find(|(name, _, _)| name.eq_ignore_ascii_case(host))
+        .map(|(_, ipv4, ipv6)| match ip_strategy {
+            LookupIpStrategy::Ipv4Only => IpAddr::V4(*ipv4),
+            _ => IpAddr::V6(*ipv6),
+        })
+}
+
+/// A resolver that is built on first use, and rebuilt once it expires or is reset
+#[derive(Debug, Default)]
+pub(crate) struct ResolverCache(Mutex<Option<(TokioResolver, Option<Instant>)>>);
+
+impl Clone for ResolverCache {
+    fn clone(&self) -> Self {
+        Self(Mutex::new(
+            self.0.lock().ok().and_then(|cached| cached.clone()),
+        ))
+    }
+}
+
+impl ResolverCache {
+    /// The cached resolver, if there is one and it hasn't expired
+    pub(crate) fn get(&self) -> Option<TokioResolver> {
+        match &*self.0.lock().ok()? {
+            Some((resolver, valid_until))
+                if valid_until.is_none_or(|valid_until| Instant::now() < valid_until) =>
+            {
+                Some(resolver.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Cache a resolver until `valid_until`, or until reset if that is `None`
+    fn set(&self, resolver: TokioResolver, valid_until: Option<Instant>) {
+        if let Ok(mut cached) = self.0.lock() {
+            *cached = Some((resolver, valid_until));
+        }
+    }
+
+    /// Forget the cached resolver
+    pub(crate) fn reset(&self) {
+        if let Ok(mut cached) = self.0.lock() {
+            *cached = None;
+        }
+    }
+}
+
 /// Discovers external (WAN) IP addresses using DNS queries
 ///
 /// This function determines the machine's external IP address by querying
@@ -80,7 +517,11 @@ pub enum IpVersion {
 /// 2. Creates a DNS resolver using the first nameserver that responds
 /// 3. Queries the special DNS record to get the client's IP address
 ///
-/// For performance, the function caches DNS resolvers for subsequent calls.
+/// Failed attempts are retried, and the lookup timed out, according to the
+/// default `retry::RetryPolicy`.
+/// For performance, the function caches DNS resolvers for subsequent calls;
+/// call `reset` to forget them after the network changes. To ask a different
+/// nameserver or record, use `provider::Google::new`.
 ///
 /// # Arguments
 ///
@@ -95,74 +536,245 @@ pub enum IpVersion {
 /// Returns an error if DNS resolution fails for all nameservers or if
 /// the special DNS record cannot be queried successfully.
 pub async fn find_wan_ip(strategy: IpVersion) -> Result<MyIps> {
-    // Use tokio's OnceCell for async initialization
-    static IPV4_DNS_RESOLVER: OnceCell<TokioResolver> = OnceCell::const_new();
-    static IPV6_DNS_RESOLVER: tokio::sync::OnceCell<TokioResolver> =
-        tokio::sync::OnceCell::const_new();
-
-    // Try to use cached resolver first
-    let resolver_cell = match strategy {
-        Ipv4 => &IPV4_DNS_RESOLVER,
-        Ipv6 => &IPV6_DNS_RESOLVER,
+    use provider::WanProvider;
+
+    let policy = retry::RetryPolicy::default();
+    let lookup = policy.retry(provider::Google::NAME, || GOOGLE.find_wan_ip(strategy));
+    policy
+        .deadline(&format!("WAN {strategy:?} lookup"), lookup)
+        .await
+}
+
+/// Get a resolver that talks directly to one of a set of nameservers
+///
+/// The nameserver hostnames are resolved in a race, and a resolver is built
+/// for the first one that resolves successfully. The resolver is stored in
+/// `resolver_cache` so that subsequent calls can skip the lookup entirely,
+/// until the TTL of the nameserver's address runs out.
+///
+/// # Arguments
+///
+/// * `resolver_cache` - Where to cache the resolver once it has been built
+/// * `nameservers` - The nameservers to choose from, as hostnames or IP
+///   addresses, optionally followed by a port
+/// * `strategy` - The IP version to use when talking to the nameserver
+/// * `dns` - How to send queries, both to the nameserver and when resolving
+///   its hostname
+///
+/// # Returns
+///
+/// A resolver that sends its queries to one of the nameservers
+///
+/// # Errors
+///
+/// Returns an error listing every failure if none of the nameserver hostnames
+/// can be resolved, or if the resolver cannot be built. Retrying is left to the
+/// caller.
+pub(crate) async fn nameserver_resolver<S: AsRef<str> + Sync>(
+    resolver_cache: &ResolverCache,
+    nameservers: &[S],
+    strategy: IpVersion,
+    dns: DnsOptions,
+) -> Result<TokioResolver> {
+    if nameservers.is_empty() {
+        return Err(miette!("No nameservers to query"));
+    }
+
+    // If we already have a resolver, use it directly
+    if let Some(resolver) = resolver_cache.get() {
+        return Ok(resolver);
+    }
+
+    // Otherwise, we need to create a new resolver
+    let lookup_ip_strategy = match strategy {
+        Ipv4 => LookupIpStrategy::Ipv4Only,
+        Ipv6 => LookupIpStrategy::Ipv6Only,
     };
 
-    // Add retry logic with 3 attempts
-    let mut retries = 3;
-    loop {
-        // If we already have a resolver, use it directly
-        if let Some(resolver) = resolver_cell.get() {
-            return user_ips(resolver).await;
-        }
+    // Race the nameservers and use the first one that resolves
+    let (addr, valid_until, host) = first_success(
+        "nameserver",
+        nameservers.iter().map(|nameserver| {
+            let nameserver = nameserver.as_ref();
+            let lookup = async move {
+                let (host, _) = split_nameserver(nameserver)?;
+                let (addr, valid_until) = nameserver_addr_until(nameserver, strategy, dns).await?;
+                Ok((addr, valid_until, host))
+            };
+            (nameserver.to_string(), lookup)
+        }),
+        dns.stagger,
+    )
+    .await?;
+
+    // Create and cache the resolver
+    let resolver = resolver_at(addr, host, lookup_ip_strategy, dns)?;
+    resolver_cache.set(resolver.clone(), valid_until);
+    Ok(resolver)
+}
 
-        // Otherwise, we need to create a new resolver
-        let lookup_ip_strategy = match strategy {
-            Ipv4 => LookupIpStrategy::Ipv4Only,
-            Ipv6 => LookupIpStrategy::Ipv6Only,
-        };
+/// Race several lookups, returning the first that succeeds
+///
+/// Failures don't end the race; the remaining lookups carry on until one of
+/// them succeeds. With a `stagger`, lookups are started one at a time, each
+/// after the previous has either failed or been running for `stagger`, in the
+/// style of happy eyeballs.
+///
+/// # Arguments
+///
+/// * `what` - What each lookup is for, used in the error message
+/// * `lookups` - Each lookup, labelled with a name for the error message
+/// * `stagger` - How long to give each lookup before starting the next; if
+///   `None` they are all started at once
+///
+/// # Returns
+///
+/// The result of the first lookup to succeed
+///
+/// # Errors
+///
+/// Returns an error listing every failure if all of the lookups fail, which is
+/// `retry::Transient` if any of them was, or if there are no lookups
+pub(crate) async fn first_success<T, F>(
+    what: &str,
+    lookups: impl IntoIterator<Item = (String, F)>,
+    stagger: Option<Duration>,
+) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let labelled = |(label, lookup): (String, F)| async move { (label, lookup.await) };
+
+    let mut pending = lookups.into_iter().collect::<Vec<_>>().into_iter();
+    let mut running = FuturesUnordered::new();
+    let mut failures = Vec::new();
+    let mut transient = false;
+
+    running.extend(pending.next().map(labelled));
+    if stagger.is_none() {
+        running.extend(pending.by_ref().map(labelled));
+    }
 
-        // Try all nameservers in parallel and use the first one that responds
-        let ns_ip = tokio::select! {
-            ns_ip = async {
-                resolver_ip(
-                    GOOGLE_NS1,
-                    lookup_ip_strategy
-                ).await
-            } => ns_ip,
-            ns_ip = async {
-                resolver_ip(
-                    GOOGLE_NS2,
-                    lookup_ip_strategy
-                ).await
-            } => ns_ip,
-            ns_ip = async {
-                resolver_ip(
-                    GOOGLE_NS3,
-                    lookup_ip_strategy
-                ).await
-            } => ns_ip,
-            ns_ip = async {
-                resolver_ip(
-                    GOOGLE_NS4,
-                    lookup_ip_strategy
-                ).await
-            } => ns_ip,
+    while !running.is_empty() {
+        let finished = match stagger.filter(|_| pending.len() > 0) {
+            Some(delay) => tokio::time::timeout(delay, running.next()).await.ok(),
+            None => Some(running.next().await),
         };
 
-        match ns_ip {
-            Ok(ip) => {
-                // Create and cache the resolver
-                let dns_resolver = resolver_cell
-                    .get_or_try_init(|| async { resolver(ip, lookup_ip_strategy) })
-                    .await?;
-                return user_ips(dns_resolver).await;
-            }
-            Err(_e) if retries > 0 => {
-                retries -= 1;
-                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        match finished {
+            // Nothing has finished in time, so start on the next one as well
+            None => running.extend(pending.next().map(labelled)),
+            Some(Some((_, Ok(value)))) => return Ok(value),
+            Some(Some((label, Err(e)))) => {
+                transient |= retry::is_transient(&e);
+                failures.push(format!("{label}: {e}"));
+
+                // A failure means there's no need to wait before trying the next
+                if running.is_empty() {
+                    running.extend(pending.next().map(labelled));
+                }
             }
-            Err(e) => return Err(e),
+            Some(None) => break,
         }
     }
+
+    if failures.is_empty() {
+        return Err(miette!("No {what}s to try"));
+    }
+
+    let message = format!("Every {what} failed: {}", failures.join("; "));
+    if transient {
+        return Err(retry::Transient::new(message).into());
+    }
+
+    Err(miette!(message))
+}
+
+/// Work out the address to send queries to for a nameserver
+///
+/// # Arguments
+///
+/// * `nameserver` - A hostname or IP address, optionally followed by a port,
+///   e.g. `ns1.google.com`, `192.0.2.53:5353` or `[2001:db8::53]:5353`
+/// * `strategy` - The IP version to use when talking to the nameserver
+/// * `dns` - How to send queries when resolving the nameserver's hostname
+///
+/// # Returns
+///
+/// The socket address of the nameserver, using the default port for the
+/// transport if none was given
+///
+/// # Errors
+///
+/// Returns an error if a hostname cannot be resolved, or an IP address is
+/// not of the requested version
+pub async fn nameserver_addr(
+    nameserver: &str,
+    strategy: IpVersion,
+    dns: DnsOptions,
+) -> Result<SocketAddr> {
+    Ok(nameserver_addr_until(nameserver, strategy, dns).await?.0)
+}
+
+/// Work out the address for a nameserver, and how long it can be relied on
+///
+/// The address of a nameserver given by hostname is only valid until its TTL
+/// runs out; one given by IP address is valid forever, marked by `None`.
+///
+/// # Errors
+///
+/// Returns an error if a hostname cannot be resolved, or an IP address is
+/// not of the requested version
+async fn nameserver_addr_until(
+    nameserver: &str,
+    strategy: IpVersion,
+    dns: DnsOptions,
+) -> Result<(SocketAddr, Option<Instant>)> {
+    let (host, port) = split_nameserver(nameserver)?;
+    let port = port.unwrap_or_else(|| dns.transport.default_port());
+
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return match (strategy, ip) {
+            (Ipv4, IpAddr::V4(_)) | (Ipv6, IpAddr::V6(_)) => Ok((SocketAddr::new(ip, port), None)),
+            _ => Err(miette!(
+                "Nameserver {nameserver} is not an {strategy:?} address"
+            )),
+        };
+    }
+
+    let lookup_ip_strategy = match strategy {
+        Ipv4 => LookupIpStrategy::Ipv4Only,
+        Ipv6 => LookupIpStrategy::Ipv6Only,
+    };
+
+    let (ip, valid_until) = resolve_host(host, lookup_ip_strategy, dns).await?;
+    Ok((SocketAddr::new(ip, port), Some(valid_until)))
+}
+
+/// Split a nameserver into its host and, if one was given, its port
+///
+/// IPv6 addresses may be written bare or in brackets; the brackets are
+/// removed from the host.
+///
+/// # Errors
+///
+/// Returns an error if the port is not a number
+fn split_nameserver(nameserver: &str) -> Result<(&str, Option<u16>)> {
+    let unbracketed = nameserver.trim_start_matches('[').trim_end_matches(']');
+    if unbracketed.parse::<IpAddr>().is_ok() {
+        return Ok((unbracketed, None));
+    }
+
+    match nameserver.rsplit_once(':') {
+        Some((host, port)) => Ok((
+            host.trim_start_matches('[').trim_end_matches(']'),
+            Some(
+                port.parse()
+                    .map_err(|_| miette!("Invalid port in nameserver '{nameserver}'"))?,
+            ),
+        )),
+        None => Ok((nameserver, None)),
+    }
 }
 
 /// Discovers IP addresses from local network interfaces
@@ -189,19 +801,49 @@ pub async fn find_wan_ip(strategy: IpVersion) -> Result<MyIps> {
 /// gracefully by returning an empty vector rather than propagating the error.
 /// It will only return an error in exceptional circumstances.
 pub fn find_local_ip(strategy: Option<IpVersion>) -> Result<MyIps> {
+    Ok(find_local_ip_with(strategy, &InterfaceFilter::default())?
+        .into_iter()
+        .map(myip::MyIp::from)
+        .collect())
+}
+
+/// Discovers IP addresses from the chosen local network interfaces
+///
+/// Like `find_local_ip`, but only lists addresses `interfaces` keeps, and
+/// labels each with its interface, prefix length and flags.
+///
+/// # Arguments
+///
+/// * `strategy` - Optional IP version filter, as for `find_local_ip`
+/// * `interfaces` - Which network interfaces to list addresses from, and
+///   which of their addresses to leave out by their flags
+///
+/// # Returns
+///
+/// A vector of addresses matching the specified filters, each labelled with
+/// the name of its interface
+///
+/// # Errors
+///
+/// As for `find_local_ip`
+pub fn find_local_ip_with(
+    strategy: Option<IpVersion>,
+    interfaces: &InterfaceFilter,
+) -> Result<Addresses> {
     // Pre-allocate with a reasonable capacity to avoid reallocations
     let mut result = Vec::with_capacity(8);
 
     // Early return if we can't get network interfaces
-    let Ok(netifas) = list_afinet_netifas() else {
+    let Ok(mut addresses) = interface::list_addresses() else {
         return Ok(result);
     };
+    addresses.retain(|address| interfaces.keeps(address));
 
     // Optimize the common case (no filter) to avoid match overhead in the loop
     if strategy.is_none() {
-        result.reserve(netifas.len());
-        for (_, ip) in netifas {
-            result.push(myip::MyIp::new_plain(ip));
+        result.reserve(addresses.len());
+        for address in addresses {
+            result.push(address.into());
         }
         return Ok(result);
     }
@@ -209,16 +851,16 @@ pub fn find_local_ip(strategy: Option<IpVersion>) -> Result<MyIps> {
     // For filtered cases, use a more direct approach
     match strategy {
         Some(Ipv4) => {
-            for (_, ip) in netifas {
-                if !ip.is_ipv6() {
-                    result.push(myip::MyIp::new_plain(ip));
+            for address in addresses {
+                if !address.ip.is_ipv6() {
+                    result.push(address.into());
                 }
             }
         }
         Some(Ipv6) => {
-            for (_, ip) in netifas {
-                if ip.is_ipv6() {
-                    result.push(myip::MyIp::new_plain(ip));
+            for address in addresses {
+                if address.ip.is_ipv6() {
+                    result.push(address.into());
                 }
             }
         }
@@ -228,14 +870,98 @@ pub fn find_local_ip(strategy: Option<IpVersion>) -> Result<MyIps> {
     Ok(result)
 }
 
-/// Queries a DNS resolver to retrieve the client's external IP addresses
+/// Lists the local addresses WAN lookups can be sent from, with their interface names
 ///
-/// This function performs a DNS TXT record lookup for a special domain
-/// (`o-o.myaddr.l.google.com`) that returns the client's IP address as seen
-/// by the DNS server. This technique is used to determine external (WAN) IP
-/// addresses without relying on third-party web services.
+/// Loopback, link-local, multicast and unspecified addresses are left out,
+/// as traffic sent from them never reaches the internet. Each remaining
+/// address can be used as a `SourceAddresses` to find the public address it
+/// egresses as.
 ///
-/// The function parses the TXT record responses and converts them to IP addresses.
+/// # Arguments
+///
+/// * `strategy` - Optional IP version filter, as for `find_local_ip`
+/// * `interfaces` - Which network interfaces to list addresses from
+///
+/// # Returns
+///
+/// The name of each interface along with one of its addresses, in the order
+/// the operating system lists them
+///
+/// # Errors
+///
+/// Returns an error if the network interfaces cannot be listed
+pub fn find_routable_local_ips(
+    strategy: Option<IpVersion>,
+    interfaces: &InterfaceFilter,
+) -> Result<Vec<(String, IpAddr)>> {
+    Ok(interface::list_addresses()?
+        .into_iter()
+        .filter(|address| interfaces.matches(&address.name))
+        .map(|address| (address.name, address.ip))
+        .filter(|(_, ip)| match strategy {
+            Some(Ipv4) => ip.is_ipv4(),
+            Some(Ipv6) => ip.is_ipv6(),
+            None => true,
+        })
+        .filter(|(_, ip)| is_routable(*ip))
+        .collect())
+}
+
+/// Whether traffic sent from an address can reach the internet
+const fn is_routable(ip: IpAddr) -> bool {
+    let link_local = match ip {
+        IpAddr::V4(ip) => ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+    };
+
+    !(link_local || ip.is_loopback() || ip.is_multicast() || ip.is_unspecified())
+}
+
+/// What a whoami TXT record, such as `MYADDR_RECORD`, says about the client
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UserIps {
+    /// The addresses the query was seen to come from
+    pub ips: MyIps,
+    /// The subnet the resolver passed on to the nameserver, when the query
+    /// went through a recursive resolver that uses EDNS Client Subnet
+    pub client_subnet: Option<myip::ClientSubnet>,
+}
+
+impl UserIps {
+    /// Read the answers to a whoami TXT record
+    ///
+    /// Answers that are IP addresses are the addresses the query came from,
+    /// and an `edns0-client-subnet` answer is the subnet the resolver passed
+    /// on. Anything else is ignored.
+    ///
+    /// # Arguments
+    ///
+    /// * `answers` - The text of each TXT record in the answer
+    #[must_use]
+    pub fn from_txt<S: AsRef<str>>(answers: impl IntoIterator<Item = S>) -> Self {
+        // Most of the time we'll get 1-2 IPs (IPv4 and/or IPv6)
+        let mut result = Self {
+            ips: Vec::with_capacity(2),
+            client_subnet: None,
+        };
+
+        for answer in answers {
+            let answer = answer.as_ref();
+            if let Ok(ip) = IpAddr::from_str(answer) {
+                result.ips.push(myip::MyIp::new_plain(ip));
+            } else if let Some(subnet) = myip::ClientSubnet::from_txt(answer) {
+                result.client_subnet = Some(subnet);
+            }
+        }
+
+        result
+    }
+}
+
+/// Queries a DNS resolver to retrieve the client's external IP addresses
+///
+/// This function performs a DNS TXT record lookup for `MYADDR_RECORD`, that
+/// returns the client's IP address as seen by the DNS server.
 ///
 /// # Arguments
 ///
@@ -252,27 +978,81 @@ pub fn find_local_ip(strategy: Option<IpVersion>) -> Result<MyIps> {
 /// - The resolver encounters network issues
 /// - The TXT records cannot be retrieved
 pub async fn user_ips(resolver: &Resolver<TokioRuntimeProvider>) -> Result<MyIps> {
+    Ok(user_ips_with(resolver, MYADDR_RECORD).await?.ips)
+}
+
+/// Queries a DNS resolver to retrieve the client's external IP addresses
+///
+/// This function performs a DNS TXT record lookup for a special domain,
+/// such as `o-o.myaddr.l.google.com`, that returns the client's IP address as
+/// seen by the DNS server. This technique is used to determine external (WAN) IP
+/// addresses without relying on third-party web services.
+///
+/// The function parses the TXT record responses into IP addresses, along with
+/// any EDNS client subnet reported when the query goes through a recursive
+/// resolver.
+///
+/// # Arguments
+///
+/// * `resolver` - A configured DNS resolver to use for the query
+/// * `record` - The TXT record to look up, usually `MYADDR_RECORD`
+///
+/// # Returns
+///
+/// A result containing the IP addresses, and client subnet if there was one,
+/// obtained from the DNS response
+///
+/// # Errors
+///
+/// Returns an error if:
+/// - The DNS lookup fails to complete
+/// - The resolver encounters network issues
+/// - The TXT records cannot be retrieved
+pub async fn user_ips_with(
+    resolver: &Resolver<TokioRuntimeProvider>,
+    record: &str,
+) -> Result<UserIps> {
     // Perform the DNS lookup
-    let txt_records = resolver.txt_lookup(MYADDR_RECORD).await.into_diagnostic()?;
+    let txt_records = resolver.txt_lookup(record).await.into_transient()?;
 
-    // Pre-allocate the result vector with a reasonable capacity
-    // Most of the time we'll get 1-2 IPs (IPv4 and/or IPv6)
-    let mut result = Vec::with_capacity(2);
+    Ok(UserIps::from_txt(txt_records.answers().iter().filter_map(
+        |record| match &record.data {
+            RData::TXT(txt) => Some(txt.to_string()),
+            _ => None,
+        },
+    )))
+}
 
-    // Process each TXT record
-    for record in txt_records.answers() {
-        // Extract IP from TXT record data
-        if let RData::TXT(txt) = &record.data {
-            let ip_str = txt.to_string();
+/// Find the address the system's recursive resolver asks nameservers from
+///
+/// Rather than sending the whoami query straight to Google's nameservers,
+/// this sends it through the resolver the system is configured with, so
+/// Google sees, and answers with, the address of that resolver instead of
+/// ours. Comparing the two shows where DNS queries leave the network, such as
+/// when they leak outside of a VPN tunnel. The client subnet the resolver
+/// passed on, if any, is included.
+///
+/// # Arguments
+///
+/// * `record` - The TXT record to look up, usually `MYADDR_RECORD`
+/// * `dns` - How to send the query
+///
+/// # Returns
+///
+/// The resolver's egress addresses, and the client subnet if there was one
+///
+/// # Errors
+///
+/// Returns an error if the system resolver cannot be built, or the lookup fails
+pub async fn resolver_egress(record: &str, dns: DnsOptions) -> Result<UserIps> {
+    let resolver = cached_system_resolver(LookupIpStrategy::default(), dns)?;
+    let answer = user_ips_with(&resolver, record).await?;
 
-            // Try to parse as IP address
-            if let Ok(ip) = IpAddr::from_str(&ip_str) {
-                result.push(myip::MyIp::new_plain(ip));
-            }
-        }
+    if answer.ips.is_empty() {
+        return Err(miette!("No resolver address found in {record}"));
     }
 
-    Ok(result)
+    Ok(answer)
 }
 
 /// Create a DNS resolver that uses a specific nameserver
@@ -290,16 +1070,132 @@ pub async fn user_ips(resolver: &Resolver<TokioRuntimeProvider>) -> Result<MyIps
 ///
 /// Returns an error if the resolver cannot be built
 pub fn resolver(ip: IpAddr, ip_strategy: LookupIpStrategy) -> Result<TokioResolver> {
+    resolver_at(
+        SocketAddr::new(ip, Transport::Udp.default_port()),
+        &ip.to_string(),
+        ip_strategy,
+        DnsOptions::default(),
+    )
+}
+
+/// Create a DNS resolver that uses a specific nameserver on any port and transport
+///
+/// # Arguments
+///
+/// * `addr` - The IP address and port of the nameserver to use
+/// * `server_name` - The name of the nameserver, checked against its
+///   certificate when using TLS or HTTPS
+/// * `ip_strategy` - The IP version strategy to use for lookups
+/// * `dns` - How to send queries to the nameserver, and where from
+///
+/// # Returns
+///
+/// A configured DNS resolver
+///
+/// # Errors
+///
+/// Returns an error if there is no source address of the nameserver's IP
+/// version, or the resolver cannot be built
+pub fn resolver_at(
+    addr: SocketAddr,
+    server_name: &str,
+    ip_strategy: LookupIpStrategy,
+    dns: DnsOptions,
+) -> Result<TokioResolver> {
+    let mut name_server = dns.transport.name_server(addr, server_name);
+    let bind_addr = dns.source.bind_addr(addr.ip())?;
+    for connection in &mut name_server.connections {
+        connection.bind_addr = bind_addr;
+    }
+
     let mut builder = Resolver::builder_with_config(
-        ResolverConfig::from_parts(None, vec![], vec![NameServerConfig::udp_and_tcp(ip)]),
+        ResolverConfig::from_parts(None, vec![], vec![name_server]),
         TokioRuntimeProvider::default(),
     );
     builder.options_mut().ip_strategy = ip_strategy;
     builder.build().into_diagnostic()
 }
 
+/// Create a DNS resolver for general lookups, such as nameserver hostnames
+///
+/// Plain UDP and TCP queries go to the nameservers in the system
+/// configuration. The system's nameservers rarely accept encrypted queries,
+/// and there is no name to check their certificates against, so TLS and
+/// HTTPS queries go to Cloudflare's public resolvers instead.
+///
+/// # Arguments
+///
+/// * `ip_strategy` - The IP version strategy to use for lookups
+/// * `dns` - How to send queries
+///
+/// # Returns
+///
+/// A configured DNS resolver
+///
+/// # Errors
+///
+/// Returns an error if the system configuration cannot be read, or the
+/// resolver cannot be built
+pub fn system_resolver(ip_strategy: LookupIpStrategy, dns: DnsOptions) -> Result<TokioResolver> {
+    let mut builder = match dns.transport {
+        Transport::Udp => Resolver::builder_tokio().into_diagnostic()?,
+        Transport::Tcp => {
+            let (config, options) =
+                hickory_resolver::system_conf::read_system_conf().into_diagnostic()?;
+            let (domain, search, mut name_servers) = config.into_parts();
+            for name_server in &mut name_servers {
+                name_server
+                    .connections
+                    .retain(|connection| connection.protocol == ProtocolConfig::Tcp);
+            }
+
+            let mut builder = Resolver::builder_with_config(
+                ResolverConfig::from_parts(domain, search, name_servers),
+                TokioRuntimeProvider::default(),
+            );
+            *builder.options_mut() = options;
+            builder
+        }
+        Transport::Tls => Resolver::builder_with_config(
+            ResolverConfig::tls(&CLOUDFLARE),
+            TokioRuntimeProvider::default(),
+        ),
+        Transport::Https => Resolver::builder_with_config(
+            ResolverConfig::https(&CLOUDFLARE),
+            TokioRuntimeProvider::default(),
+        ),
+    };
+    builder.options_mut().ip_strategy = ip_strategy;
+    builder.build().into_diagnostic()
+}
+
+/// Get a system resolver, building it the first time it is asked for
+///
+/// # Errors
+///
+/// Returns an error if the resolver cannot be built
+fn cached_system_resolver(ip_strategy: LookupIpStrategy, dns: DnsOptions) -> Result<TokioResolver> {
+    if let Ok(resolvers) = SYSTEM_RESOLVERS.lock()
+        && let Some((_, _, resolver)) = resolvers.iter().find(|(cached_strategy, cached_dns, _)| {
+            *cached_strategy == ip_strategy && *cached_dns == dns
+        })
+    {
+        return Ok(resolver.clone());
+    }
+
+    let resolver = system_resolver(ip_strategy, dns)?;
+
+    if let Ok(mut resolvers) = SYSTEM_RESOLVERS.lock() {
+        resolvers.push((ip_strategy, dns, resolver.clone()));
+    }
+
+    Ok(resolver)
+}
+
 /// Resolve a nameserver hostname to an IP address
 ///
+/// The lookup is sent as the system resolver sends it. See `resolver_ip_with`.
+///
 /// # Arguments
 ///
 /// * `ns_host` - The hostname of the nameserver to resolve
@@ -313,19 +1209,92 @@ pub fn resolver(ip: IpAddr, ip_strategy: LookupIpStrategy) -> Result<TokioResolv
 ///
 /// Returns an error if the DNS lookup fails or if no IP address is found for the given hostname
 pub async fn resolver_ip(ns_host: &str, ip_strategy: LookupIpStrategy) -> Result<IpAddr> {
-    use std::collections::HashMap;
-    use std::sync::{LazyLock, Mutex};
+    resolver_ip_with(ns_host, ip_strategy, DnsOptions::default()).await
+}
+
+/// Resolve a nameserver hostname to an IP address, sending the lookup as
+/// `dns` says
+///
+/// Addresses are cached until their TTL runs out, or until `reset` is called.
+/// Nameservers listed in `BUILTIN_ADDRESSES` fall back to their built-in
+/// address if the system resolver fails or is slow, and use it straight away
+/// with `Bootstrap::Builtin`.
+///
+/// # Arguments
+///
+/// * `ns_host` - The hostname of the nameserver to resolve
+/// * `ip_strategy` - The IP version strategy to use for the lookup
+/// * `dns` - How to send the query
+///
+/// # Returns
+///
+/// A result containing the IP address of the nameserver if successful
+///
+/// # Errors
+///
+/// Returns an error if the DNS lookup fails or if no IP address is found for the given hostname
+pub async fn resolver_ip_with(
+    ns_host: &str,
+    ip_strategy: LookupIpStrategy,
+    dns: DnsOptions,
+) -> Result<IpAddr> {
+    Ok(resolve_host(ns_host, ip_strategy, dns).await?.0)
+}
+
+/// Resolve a nameserver hostname to an IP address, and when that address
+/// expires, falling back to its built-in address if it has one
+///
+/// # Errors
+///
+/// Returns an error if the DNS lookup fails or if no IP address is found for
+/// the given hostname, and it has no built-in address
+async fn resolve_host(
+    ns_host: &str,
+    ip_strategy: LookupIpStrategy,
+    dns: DnsOptions,
+) -> Result<(IpAddr, Instant)> {
+    let Some(builtin) = builtin_address(ns_host, ip_strategy) else {
+        return system_resolve_host(ns_host, ip_strategy, dns).await;
+    };
+    if dns.bootstrap == Bootstrap::Builtin {
+        return Ok((builtin, Instant::now() + BUILTIN_TTL));
+    }
+
+    // A broken resolver can take longer to fail than a whole lookup attempt
+    let reason = match tokio::time::timeout(
+        BOOTSTRAP_TIMEOUT,
+        system_resolve_host(ns_host, ip_strategy, dns),
+    )
+    .await
+    {
+        Ok(Ok(found)) => return Ok(found),
+        Ok(Err(e)) => e.to_string(),
+        Err(_) => format!("no answer within {BOOTSTRAP_TIMEOUT:?}"),
+    };
 
-    // Static caches for resolved IPs to avoid repeated lookups
-    static IPV4_CACHE: LazyLock<Mutex<HashMap<String, IpAddr>>> =
-        LazyLock::new(|| Mutex::new(HashMap::new()));
-    static IPV6_CACHE: LazyLock<Mutex<HashMap<String, IpAddr>>> =
-        LazyLock::new(|| Mutex::new(HashMap::new()));
+    if let Ok(mut fallbacks) = BOOTSTRAP_FALLBACKS.lock() {
+        fallbacks.retain(|fallback| fallback.host != ns_host);
+        fallbacks.push(BootstrapFallback {
+            host: ns_host.to_string(),
+            ip: builtin,
+            reason,
+        });
+    }
 
-    // Use tokio's OnceCell for async initialization
-    static IPV4_RESOLVER: OnceCell<TokioResolver> = OnceCell::const_new();
-    static IPV6_RESOLVER: tokio::sync::OnceCell<TokioResolver> = tokio::sync::OnceCell::const_new();
+    Ok((builtin, Instant::now() + BUILTIN_TTL))
+}
 
+/// Resolve a nameserver hostname to an IP address using the system resolver,
+/// and when that address expires
+///
+/// # Errors
+///
+/// Returns an error if the DNS lookup fails or if no IP address is found for the given hostname
+async fn system_resolve_host(
+    ns_host: &str,
+    ip_strategy: LookupIpStrategy,
+    dns: DnsOptions,
+) -> Result<(IpAddr, Instant)> {
     // Select the appropriate cache based on IP strategy
     let cache = match ip_strategy {
         LookupIpStrategy::Ipv4Only => &IPV4_CACHE,
@@ -335,48 +1304,31 @@ pub async fn resolver_ip(ns_host: &str, ip_strategy: LookupIpStrategy) -> Result
     // Try to get from cache first (using std::sync::Mutex instead of tokio::sync::Mutex)
     // This avoids an await point and is more efficient for read-heavy workloads
     if let Ok(cache_guard) = cache.lock()
-        && let Some(ip) = cache_guard.get(ns_host)
+        && let Some((ip, valid_until)) = cache_guard.get(ns_host)
+        && Instant::now() < *valid_until
     {
-        return Ok(*ip);
+        return Ok((*ip, *valid_until));
     }
 
     // Get or initialize the appropriate resolver based on the IP strategy
     let resolver = match ip_strategy {
-        LookupIpStrategy::Ipv4Only => {
-            IPV4_RESOLVER
-                .get_or_try_init::<miette::Error, _, _>(|| async {
-                    let mut builder = Resolver::builder_tokio().into_diagnostic()?;
-                    builder.options_mut().ip_strategy = LookupIpStrategy::Ipv4Only;
-                    builder.build().into_diagnostic()
-                })
-                .await
-        }
-        _ => {
-            IPV6_RESOLVER
-                .get_or_try_init(|| async {
-                    let mut builder = Resolver::builder_tokio().into_diagnostic()?;
-                    builder.options_mut().ip_strategy = LookupIpStrategy::Ipv6Only;
-                    builder.build().into_diagnostic()
-                })
-                .await
-        }
+        LookupIpStrategy::Ipv4Only => cached_system_resolver(LookupIpStrategy::Ipv4Only, dns),
+        _ => cached_system_resolver(LookupIpStrategy::Ipv6Only, dns),
     }?;
 
     // Perform the lookup
-    let ip = resolver
-        .lookup_ip(ns_host)
-        .await
-        .into_diagnostic()?
+    let lookup = resolver.lookup_ip(ns_host).await.into_transient()?;
+    let ip = lookup
         .iter()
         .next()
         .ok_or_else(|| miette!("Nameserver ip not found"))?;
 
-    // Cache the result for future use (using std::sync::Mutex)
+    // Cache the result until its TTL runs out (using std::sync::Mutex)
     if let Ok(mut cache_guard) = cache.lock() {
-        cache_guard.insert(ns_host.to_string(), ip);
+        cache_guard.insert(ns_host.to_string(), (ip, lookup.valid_until()));
     }
 
-    Ok(ip)
+    Ok((ip, lookup.valid_until()))
 }
 
 /// Perform a reverse DNS lookup on an IP address
@@ -389,18 +1341,23 @@ pub async fn resolver_ip(ns_host: &str, ip_strategy: LookupIpStrategy) -> Result
 ///
 /// An option containing the reverse DNS entry if successful
 pub async fn reverse_ip(ip: &myip::MyIp) -> Option<myip::ReversedIp> {
-    // Create a resolver only once
-    static RESOLVER: OnceCell<TokioResolver> = OnceCell::const_new();
-
-    let resolver = RESOLVER
-        .get_or_try_init::<miette::Error, _, _>(|| async {
-            Resolver::builder_tokio()
-                .into_diagnostic()?
-                .build()
-                .into_diagnostic()
-        })
-        .await
-        .ok()?;
+    reverse_ip_with(ip, DnsOptions::default()).await
+}
+
+/// Perform a reverse DNS lookup on an IP address, sending the query as `dns`
+/// says
+///
+/// # Arguments
+///
+/// * `ip` - The IP address to look up
+/// * `dns` - How to send the query
+///
+/// # Returns
+///
+/// An option containing the reverse DNS entry if successful
+pub async fn reverse_ip_with(ip: &myip::MyIp, dns: DnsOptions) -> Option<myip::ReversedIp> {
+    // The resolver is built only once for each set of options, until reset
+    let resolver = cached_system_resolver(LookupIpStrategy::default(), dns).ok()?;
 
     resolver
         .reverse_lookup(ip.ip())
@@ -437,6 +1394,37 @@ pub async fn reverse_ip(ip: &myip::MyIp) -> Option<myip::ReversedIp> {
 /// A future that resolves to a `HashSet` of formatted IP address strings.
 /// When `do_reverse` is true, the strings will be in the format "`ip_address` (hostname)"
 pub async fn process_ips(ips: &[Result<MyIps>], do_reverse: bool) -> HashSet<String> {
+    // Failures are skipped either way, so only the successes are passed on
+    let addresses: Vec<Result<Addresses>> = ips
+        .iter()
+        .flatten()
+        .map(|ips| Ok(ips.iter().cloned().map(myip::Address::from).collect()))
+        .collect();
+
+    process_ips_with(&addresses, do_reverse, DnsOptions::default()).await
+}
+
+/// Processes addresses with optional reverse DNS resolution, sending the
+/// reverse DNS queries as `dns` says
+///
+/// Like `process_ips`, but local addresses keep their interface label.
+///
+/// # Arguments
+///
+/// * `ips` - A collection of results containing addresses to process
+/// * `do_reverse` - Boolean flag indicating whether to perform reverse DNS lookups
+/// * `dns` - How to send the reverse DNS queries
+///
+/// # Returns
+///
+/// A future that resolves to a `HashSet` of formatted address strings, as
+/// `Address` displays them. When `do_reverse` is true, the strings will be in
+/// the format "`ip_address` (hostname)", or "`ip_address` (interface, hostname)"
+pub async fn process_ips_with(
+    ips: &[Result<Addresses>],
+    do_reverse: bool,
+    dns: DnsOptions,
+) -> HashSet<String> {
     // If we don't need to do reverse lookups, we can optimize by avoiding the async processing
     if !do_reverse {
         // Estimate capacity to avoid reallocations
@@ -450,7 +1438,7 @@ pub async fn process_ips(ips: &[Result<MyIps>], do_reverse: bool) -> HashSet<Str
         // Use a single loop to avoid nested iterators
         for ip_result in ips.iter().flatten() {
             for my_ip in ip_result {
-                result.insert(my_ip.ip().to_string());
+                result.insert(my_ip.to_string());
             }
         }
 
@@ -478,9 +1466,9 @@ pub async fn process_ips(ips: &[Result<MyIps>], do_reverse: bool) -> HashSet<Str
     // Use a more reasonable concurrency limit based on typical DNS resolver limits
     stream::iter(all_ips)
         .map(|my_ip| async move {
-            reverse_ip(my_ip).await.map_or_else(
-                || my_ip.ip().to_string(),
-                |reversed_ip| format!("{} ({})", my_ip.ip(), reversed_ip.0),
+            reverse_ip_with(my_ip.my_ip(), dns).await.map_or_else(
+                || my_ip.to_string(),
+                |reversed_ip| my_ip.clone().with_reversed(reversed_ip).to_string(),
             )
         })
         .buffer_unordered(16) // Reduced from 32 to avoid overwhelming DNS resolvers
@@ -662,6 +1650,360 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn nameserver_addr_accepts_literal_addresses() -> TestResult {
+        let cases = [
+            ("192.0.2.53", Ipv4, "192.0.2.53:53"),
+            ("192.0.2.53:5353", Ipv4, "192.0.2.53:5353"),
+            ("2001:db8::53", Ipv6, "[2001:db8::53]:53"),
+            ("[2001:db8::53]", Ipv6, "[2001:db8::53]:53"),
+            ("[2001:db8::53]:5353", Ipv6, "[2001:db8::53]:5353"),
+        ];
+
+        for (nameserver, strategy, expected) in cases {
+            let actual = nameserver_addr(nameserver, strategy, DnsOptions::default())
+                .await?
+                .to_string();
+            if actual != expected {
+                return Err(miette!(
+                    "Expected '{}' for '{}', got '{}'",
+                    expected,
+                    nameserver,
+                    actual
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nameserver_addr_rejects_the_wrong_ip_version() -> TestResult {
+        if nameserver_addr("192.0.2.53", Ipv6, DnsOptions::default())
+            .await
+            .is_ok()
+        {
+            return Err(miette!(
+                "Expected an IPv4 nameserver to be rejected for IPv6"
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn nameserver_addr_uses_the_port_for_the_transport() -> TestResult {
+        let cases = [
+            (Transport::Udp, "192.0.2.53", "192.0.2.53:53"),
+            (Transport::Tcp, "192.0.2.53", "192.0.2.53:53"),
+            (Transport::Tls, "192.0.2.53", "192.0.2.53:853"),
+            (Transport::Https, "192.0.2.53", "192.0.2.53:443"),
+            (Transport::Tls, "192.0.2.53:5353", "192.0.2.53:5353"),
+        ];
+
+        for (transport, nameserver, expected) in cases {
+            let actual = nameserver_addr(
+                nameserver,
+                Ipv4,
+                DnsOptions {
+                    transport,
+                    ..DnsOptions::default()
+                },
+            )
+            .await?
+            .to_string();
+            if actual != expected {
+                return Err(miette!(
+                    "Expected '{}' for '{}' over {:?}, got '{}'",
+                    expected,
+                    nameserver,
+                    transport,
+                    actual
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn lookup(
+        label: &str,
+        delay_ms: u64,
+        result: Result<u32>,
+    ) -> (String, futures::future::BoxFuture<'static, Result<u32>>) {
+        use futures::FutureExt;
+
+        let lookup = async move {
+            tokio::time::sleep(Duration::from_millis(delay_ms)).await;
+            result
+        };
+        (label.to_string(), lookup.boxed())
+    }
+
+    #[tokio::test]
+    async fn first_success_skips_fast_failures() -> TestResult {
+        let lookups = [
+            lookup("ns1", 0, Err(miette!("refused"))),
+            lookup("ns2", 20, Ok(2)),
+        ];
+
+        let winner = first_success("nameserver", lookups, None).await?;
+        if winner != 2 {
+            return Err(miette!("Expected ns2 to win, got {winner}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn first_success_reports_every_failure() -> TestResult {
+        let lookups = [
+            lookup("ns1", 0, Err(miette!("refused"))),
+            lookup("ns2", 10, Err(miette!("timed out"))),
+        ];
+
+        let error = first_success("nameserver", lookups, None)
+            .await
+            .err()
+            .ok_or_else(|| miette!("Expected every lookup to fail"))?
+            .to_string();
+        let expected = "Every nameserver failed: ns1: refused; ns2: timed out";
+        if error != expected {
+            return Err(miette!("Expected '{expected}', got '{error}'"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn first_success_staggers_the_start_of_each_lookup() -> TestResult {
+        // ns2 would win if started at once; staggered, ns1 finishes first
+        let lookups = [lookup("ns1", 20, Ok(1)), lookup("ns2", 0, Ok(2))];
+
+        let winner = first_success("nameserver", lookups, Some(Duration::from_millis(200))).await?;
+        if winner != 1 {
+            return Err(miette!("Expected ns1 to win, got {winner}"));
+        }
+
+        // A slow lookup doesn't hold up the next one for longer than the stagger
+        let lookups = [lookup("ns1", 10_000, Ok(1)), lookup("ns2", 0, Ok(2))];
+
+        let winner = first_success("nameserver", lookups, Some(Duration::from_millis(10))).await?;
+        if winner != 2 {
+            return Err(miette!("Expected ns2 to win, got {winner}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn first_success_needs_a_lookup() -> TestResult {
+        let lookups: [(String, futures::future::Ready<Result<u32>>); 0] = [];
+        if first_success("nameserver", lookups, None).await.is_ok() {
+            return Err(miette!("Expected no lookups to be an error"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolver_cache_forgets_expired_resolvers() -> TestResult {
+        let cache = ResolverCache::default();
+        let resolver = resolver(IpAddr::from([192, 0, 2, 53]), LookupIpStrategy::Ipv4Only)?;
+
+        cache.set(resolver.clone(), None);
+        if cache.get().is_none() {
+            return Err(miette!("Expected a resolver with no expiry to be kept"));
+        }
+
+        cache.set(
+            resolver.clone(),
+            Some(Instant::now() + Duration::from_mins(1)),
+        );
+        if cache.get().is_none() {
+            return Err(miette!("Expected an unexpired resolver to be kept"));
+        }
+
+        cache.set(resolver, Some(Instant::now()));
+        if cache.get().is_some() {
+            return Err(miette!("Expected an expired resolver to be forgotten"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolver_cache_forgets_resolvers_when_reset() -> TestResult {
+        let cache = ResolverCache::default();
+        cache.set(
+            resolver(IpAddr::from([192, 0, 2, 53]), LookupIpStrategy::Ipv4Only)?,
+            None,
+        );
+
+        cache.reset();
+        if cache.get().is_some() {
+            return Err(miette!("Expected the resolver to be forgotten"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn reset_forgets_nameserver_addresses() -> TestResult {
+        let far_future = Instant::now() + Duration::from_hours(1);
+        if let Ok(mut cache) = IPV4_CACHE.lock() {
+            cache.insert(
+                "reset.example".to_string(),
+                (IpAddr::from([192, 0, 2, 53]), far_future),
+            );
+        }
+
+        reset();
+
+        let cached = IPV4_CACHE
+            .lock()
+            .map_err(|e| miette!("Cache lock poisoned: {e}"))?
+            .contains_key("reset.example");
+        if cached {
+            return Err(miette!("Expected the nameserver address to be forgotten"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn source_addresses_bind_to_the_matching_ip_version() -> TestResult {
+        let v4 = IpAddr::from([192, 0, 2, 1]);
+        let v6: IpAddr = "2001:db8::1".parse().into_diagnostic()?;
+        let source = SourceAddresses::from_ips([IpAddr::from([198, 51, 100, 7])])?;
+
+        let bound = source.bind_addr(v4)?;
+        if bound != Some("198.51.100.7:0".parse().into_diagnostic()?) {
+            return Err(miette!("Expected to bind to 198.51.100.7, got {bound:?}"));
+        }
+        if source.bind_addr(v6).is_ok() {
+            return Err(miette!("Expected no IPv6 source address"));
+        }
+
+        let unbound = SourceAddresses::default().bind_addr(v6)?;
+        if unbound.is_some() {
+            return Err(miette!("Expected the kernel to choose, got {unbound:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn source_addresses_take_one_address_of_each_version() -> TestResult {
+        let ips = [IpAddr::from([192, 0, 2, 1]), IpAddr::from([192, 0, 2, 2])];
+
+        if SourceAddresses::from_ips(ips).is_ok() {
+            return Err(miette!("Expected two IPv4 source addresses to be rejected"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_addresses_that_reach_the_internet_are_routable() -> TestResult {
+        let cases = [
+            ("192.168.1.56", true),
+            ("100.64.0.1", true),
+            ("2001:db8::5", true),
+            ("127.0.0.1", false),
+            ("169.254.1.1", false),
+            ("::1", false),
+            ("fe80::1", false),
+            ("ff02::1", false),
+            ("0.0.0.0", false),
+        ];
+
+        for (ip, expected) in cases {
+            let actual = is_routable(ip.parse().into_diagnostic()?);
+            if actual != expected {
+                return Err(miette!(
+                    "Expected {} to be routable: {}, got {}",
+                    ip,
+                    expected,
+                    actual
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn split_nameserver_separates_the_host_and_port() -> TestResult {
+        let cases = [
+            ("ns1.google.com", ("ns1.google.com", None)),
+            ("ns1.google.com:5353", ("ns1.google.com", Some(5353))),
+            ("2001:db8::53", ("2001:db8::53", None)),
+            ("[2001:db8::53]:853", ("2001:db8::53", Some(853))),
+        ];
+
+        for (nameserver, expected) in cases {
+            let actual = split_nameserver(nameserver)?;
+            if actual != expected {
+                return Err(miette!(
+                    "Expected {:?} for '{}', got {:?}",
+                    expected,
+                    nameserver,
+                    actual
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn transport_connects_with_the_right_protocol() -> TestResult {
+        let addr = SocketAddr::from(([192, 0, 2, 53], 5353));
+
+        let tls = Transport::Tls.name_server(addr, "dns.example");
+        let expected = ProtocolConfig::Tls {
+            server_name: "dns.example".into(),
+        };
+        if tls.connections.len() != 1
+            || tls.connections[0].protocol != expected
+            || tls.connections[0].port != 5353
+        {
+            return Err(miette!("Unexpected TLS connections: {:?}", tls.connections));
+        }
+
+        let udp = Transport::Udp.name_server(addr, "dns.example");
+        let protocols: Vec<_> = udp
+            .connections
+            .iter()
+            .map(|connection| &connection.protocol)
+            .collect();
+        if protocols != [&ProtocolConfig::Udp, &ProtocolConfig::Tcp] {
+            return Err(miette!("Unexpected UDP connections: {:?}", udp.connections));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn user_ips_keeps_the_client_subnet() -> TestResult {
+        let actual = UserIps::from_txt([
+            "203.0.113.7",
+            "edns0-client-subnet 198.51.100.0/24",
+            "something else",
+        ]);
+        let expected = UserIps {
+            ips: vec![myip::MyIp::new_plain(IpAddr::from([203, 0, 113, 7]))],
+            client_subnet: Some("198.51.100.0/24".parse()?),
+        };
+
+        if actual != expected {
+            return Err(miette!("Expected {:?}, got {:?}", expected, actual));
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_user_ips_parsing() -> TestResult {
         use std::str::FromStr;
@@ -686,4 +2028,46 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn builtin_addresses_match_hostnames_loosely() -> TestResult {
+        for (host, strategy, expected) in [
+            (
+                "ns1.google.com",
+                LookupIpStrategy::Ipv4Only,
+                Some(IpAddr::V4(Ipv4Addr::new(216, 239, 32, 10))),
+            ),
+            (
+                "NS1.Google.com.",
+                LookupIpStrategy::Ipv6Only,
+                Some(IpAddr::V6(Ipv6Addr::new(
+                    0x2001, 0x4860, 0x4802, 0x32, 0, 0, 0, 0xa,
+                ))),
+            ),
+            ("ns1.example.com", LookupIpStrategy::Ipv4Only, None),
+        ] {
+            let actual = builtin_address(host, strategy);
+            if actual != expected {
+                return Err(miette!("Expected {expected:?} for {host}, got {actual:?}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn builtin_bootstrap_skips_the_system_resolver() -> TestResult {
+        let dns = DnsOptions {
+            bootstrap: Bootstrap::Builtin,
+            ..DnsOptions::default()
+        };
+        let (ip, _) =
+            resolve_host("resolver1.opendns.com", LookupIpStrategy::Ipv4Only, dns).await?;
+
+        if ip != IpAddr::V4(Ipv4Addr::new(208, 67, 222, 222)) {
+            return Err(miette!("Expected the built-in address, got {ip}"));
+        }
+
+        Ok(())
+    }
 }
@@ -27,11 +27,27 @@
 
 use clap::Parser;
 use futures::future::join_all;
-use miette::{Result, bail, set_panic_hook};
+use miette::{IntoDiagnostic, Result, bail, set_panic_hook};
+use std::collections::HashSet;
 use std::hash::RandomState;
+use std::net::IpAddr;
+use whatismyip::IpVersion;
 use whatismyip::IpVersion::{Ipv4, Ipv6};
-use whatismyip::cli::Args;
-use whatismyip::{MyIps, find_local_ip, find_wan_ip, format_ips, process_ips};
+use whatismyip::cgnat::{Evidence, Verdict, egress_address};
+use whatismyip::cli::{Args, Cidr};
+use whatismyip::interface::InterfaceFilter;
+use whatismyip::myip::{Address, ClientSubnet};
+use whatismyip::nat::{self, NatBehaviour};
+use whatismyip::provider::{Google, Http, Router, Stun, WanProviders, format_answer};
+use whatismyip::proxy::Proxy;
+use whatismyip::retry::RetryPolicy;
+use whatismyip::router;
+use whatismyip::scope::Scope;
+use whatismyip::{
+    Addresses, BootstrapFallback, DnsOptions, MYADDR_RECORD, MyIps, SourceAddresses, UserIps,
+    bootstrap_fallbacks, find_local_ip_with, find_routable_local_ips, format_ips, process_ips_with,
+    resolver_egress,
+};
 /// Main entry point for the application
 ///
 /// This function:
@@ -40,19 +56,68 @@ use whatismyip::{MyIps, find_local_ip, find_wan_ip, format_ips, process_ips};
 /// 3. Executes the strategies in parallel
 /// 4. Optionally performs reverse DNS lookups
 /// 5. Formats and prints the results
+/// 6. Warns if built-in nameserver addresses had to be used
 #[tokio::main]
 async fn main() -> Result<()> {
     set_panic_hook();
     let args = Args::parse();
 
+    let result = run(&args).await;
+
+    // Whether or not the lookups worked, say if the system resolver failed
+    for fallback in bootstrap_fallbacks() {
+        eprintln!("{}", format_bootstrap_warning(&fallback));
+    }
+
+    result
+}
+
+/// Look up and print the addresses asked for on the command line
+///
+/// # Errors
+///
+/// Returns an error if no address could be found
+async fn run(args: &Args) -> Result<()> {
+    // Work out how, and where from, DNS queries should be sent
+    let dns = dns_options(args)?;
+
+    // Report each local address on its own line, rather than one list of addresses
+    if args.per_interface {
+        let report = find_wan_ip_per_interface(args, dns).await?;
+        println!("{report}");
+        return Ok(());
+    }
+
+    // Run the NAT behaviour tests instead of looking up addresses
+    if args.nat_type {
+        let behaviour = find_nat_behaviour(args, dns).await?;
+        println!("{}", format_nat_behaviour(&behaviour));
+        return Ok(());
+    }
+
+    // Work out which WAN providers to ask, and in what order
+    let providers = select_providers(args, dns)?;
+
+    // Compare the local, router and WAN addresses instead of printing them
+    if args.cgnat {
+        let evidence = find_cgnat_evidence(args, &providers, dns).await?;
+        println!("{}", format_cgnat_report(&evidence, evidence.verdict()));
+        return Ok(());
+    }
+
     // Process arguments to determine which strategies to use
-    let strategies = process_args(args);
+    let strategies = process_args(args, &providers);
 
     // Start WAN IP lookups
     let wan_handle = tokio::spawn(async move { join_all(strategies).await });
 
+    // Start looking up the resolver's address in parallel if checking for DNS leaks
+    let leak_handle = args
+        .dns_leak
+        .then(|| tokio::spawn(find_resolver_egress(args, dns)));
+
     // Start local IP lookups in parallel if needed
-    let local_results = if args.only_wan {
+    let local_results = if args.only_wan || args.dns_leak {
         vec![]
     } else {
         get_local_ips(args)
@@ -65,20 +130,429 @@ async fn main() -> Result<()> {
     results.extend(local_results);
 
     // Partition results into successes and failures
-    let (ok, failures): (Vec<Result<MyIps>>, Vec<Result<MyIps>>) =
+    let (ok, failures): (Vec<Result<Addresses>>, Vec<Result<Addresses>>) =
         results.into_iter().partition(Result::is_ok);
 
     if ok.is_empty() {
         bail!("Failed: {:?}", failures,);
     }
 
-    let processed_ips = process_ips(&ok, args.reverse).await;
+    // Keep only the addresses in the scopes asked for, unless comparing the
+    // WAN address with the resolver's, where every address matters
+    let scopes = if args.dns_leak { &[][..] } else { &args.scopes };
+    let ok = filter_scopes(ok, scopes)?;
+
+    // Drop the interface labels so that scripts get bare addresses
+    let ok = if args.bare { strip_interfaces(ok) } else { ok };
+
+    // Print the prefix, or the network, of each local address instead
+    if let Some(cidr) = args.cidr {
+        let lines = ok
+            .iter()
+            .flatten()
+            .flatten()
+            .map(|ip| format_cidr(ip, cidr))
+            .collect();
+        println!("{}", format_ips::<RandomState>(lines));
+        return Ok(());
+    }
+
+    let processed_ips = process_ips_with(&ok, args.reverse, dns).await;
+
+    if let Some(leak_handle) = leak_handle {
+        let egress = leak_handle.await.into_diagnostic()??;
+        let resolver_ips = process_ips_with(&[Ok(addresses(egress.ips))], args.reverse, dns).await;
+        println!(
+            "{}",
+            format_leak_report(processed_ips, resolver_ips, egress.client_subnet)
+        );
+        return Ok(());
+    }
+
     let resolution_result = format_ips::<RandomState>(processed_ips);
     println!("{resolution_result}");
 
     Ok(())
 }
 
+/// Select the WAN providers named on the command line
+///
+/// When no providers are named, the DNS providers in `WanProviders::DEFAULT`
+/// are used, or only the `http` provider when there is a proxy.
+/// Providers are configured from the remaining arguments before they are
+/// selected.
+///
+/// # Arguments
+///
+/// * `args` - The parsed command-line arguments
+/// * `dns` - How, and where from, DNS queries should be sent
+///
+/// # Returns
+///
+/// The providers to query, in order of preference
+///
+/// # Errors
+///
+/// Returns an error if an unknown provider is named, a provider that can't
+/// use the proxy is named, or a provider's configuration is invalid
+fn select_providers(args: &Args, dns: DnsOptions) -> Result<WanProviders> {
+    let mut providers = WanProviders::with_dns_options(dns);
+    providers.set_retry_policy(retry_policy(args));
+
+    if !args.wan_nameservers.is_empty() || args.wan_record.is_some() {
+        let nameservers = if args.wan_nameservers.is_empty() {
+            Google::NAMESERVERS.map(String::from).to_vec()
+        } else {
+            args.wan_nameservers.clone()
+        };
+        let record = args
+            .wan_record
+            .clone()
+            .unwrap_or_else(|| MYADDR_RECORD.to_string());
+
+        providers.register(Google::new(nameservers, record).with_dns_options(dns));
+    }
+    let proxy = args.proxy.as_deref().map(str::parse::<Proxy>).transpose()?;
+    if args.http_url.is_some() || proxy.is_some() {
+        let http = match &args.http_url {
+            Some(url) => Http::new(url)?,
+            None => Http::default(),
+        }
+        .with_source(dns.source);
+
+        providers.register(match proxy {
+            Some(proxy) => http.with_proxy(proxy),
+            None => http,
+        });
+    }
+    if !args.stun_servers.is_empty() {
+        providers.register(Stun::with_servers(args.stun_servers.clone()).with_source(dns.source));
+    }
+    if let Some(gateway) = args.router {
+        providers.register(
+            Router::default()
+                .with_gateway(gateway)
+                .with_source(dns.source),
+        );
+    }
+
+    // Every other provider would go around the proxy and see our own address
+    if args.proxy.is_some() {
+        if let Some(name) = args.providers.iter().find(|name| *name != Http::NAME) {
+            bail!(
+                "The {name} provider can't be used through a proxy, only the {} provider can",
+                Http::NAME
+            );
+        }
+        return providers.select(&[Http::NAME]);
+    }
+
+    if args.providers.is_empty() {
+        return providers.select(&WanProviders::DEFAULT);
+    }
+
+    providers.select(&args.providers)
+}
+
+/// Work out how DNS queries should be sent from the command-line arguments
+///
+/// # Errors
+///
+/// Returns an error if the source addresses are invalid, or the interface to
+/// send from has no usable address
+fn dns_options(args: &Args) -> Result<DnsOptions> {
+    let source = match &args.interface {
+        Some(interface) => SourceAddresses::from_interface(interface)?,
+        None => SourceAddresses::from_ips(args.source_addresses.iter().copied())?,
+    };
+
+    Ok(DnsOptions {
+        transport: args.transport,
+        stagger: args.stagger,
+        source,
+        bootstrap: args.bootstrap,
+    })
+}
+
+/// Format an address in CIDR notation, as itself or the network it's on
+///
+/// Addresses whose prefix length is unknown, such as WAN addresses, are
+/// formatted as they are.
+fn format_cidr(ip: &Address, cidr: Cidr) -> String {
+    let (Some(prefix_len), Some(network)) = (ip.prefix_len(), ip.network()) else {
+        return ip.to_string();
+    };
+
+    let (address, broadcast) = match cidr {
+        Cidr::Address => (ip.ip(), None),
+        Cidr::Network => (network, ip.broadcast()),
+    };
+    match (ip.interface(), broadcast) {
+        (Some(interface), Some(broadcast)) => {
+            format!("{address}/{prefix_len} ({interface}, broadcast {broadcast})")
+        }
+        (Some(interface), None) => format!("{address}/{prefix_len} ({interface})"),
+        (None, _) => format!("{address}/{prefix_len}"),
+    }
+}
+
+/// Drop the interface name from every local address
+fn strip_interfaces(results: Vec<Result<Addresses>>) -> Vec<Result<Addresses>> {
+    results
+        .into_iter()
+        .map(|result| result.map(|ips| ips.into_iter().map(Address::without_interface).collect()))
+        .collect()
+}
+
+/// Wrap WAN addresses, which nothing else is known about, so they can be
+/// printed along with local addresses
+fn addresses(ips: MyIps) -> Addresses {
+    ips.into_iter().map(Address::from).collect()
+}
+
+/// Keep only the addresses in one of `scopes`, or every address if there are none
+///
+/// # Errors
+///
+/// Returns an error if scopes were asked for, and none of the addresses are
+/// in them
+fn filter_scopes(
+    results: Vec<Result<Addresses>>,
+    scopes: &[Scope],
+) -> Result<Vec<Result<Addresses>>> {
+    if scopes.is_empty() {
+        return Ok(results);
+    }
+
+    let results: Vec<Result<Addresses>> = results
+        .into_iter()
+        .map(|ips| {
+            ips.map(|ips| {
+                ips.into_iter()
+                    .filter(|ip| scopes.contains(&ip.scope()))
+                    .collect()
+            })
+        })
+        .collect();
+
+    if results.iter().flatten().all(Vec::is_empty) {
+        bail!(
+            "None of the addresses found are in the scopes asked for: {}",
+            scopes
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    Ok(results)
+}
+
+/// Work out which network interfaces to list local addresses from
+fn interface_filter(args: &Args) -> InterfaceFilter {
+    InterfaceFilter {
+        include: args.only_interfaces.clone(),
+        exclude: args.exclude_interfaces.clone(),
+        no_temporary: args.no_temporary,
+        only_stable: args.only_stable,
+    }
+}
+
+/// Format a warning that a nameserver's built-in address was used
+fn format_bootstrap_warning(fallback: &BootstrapFallback) -> String {
+    format!(
+        "warning: the system resolver could not look up {} ({}), so its built-in address {} was used",
+        fallback.host, fallback.reason, fallback.ip
+    )
+}
+
+/// Work out how WAN providers should be retried from the command-line arguments
+///
+/// Anything not given on the command line keeps its default.
+fn retry_policy(args: &Args) -> RetryPolicy {
+    let default = RetryPolicy::default();
+
+    RetryPolicy {
+        attempts: args.attempts.unwrap_or(default.attempts),
+        initial_backoff: args.backoff.unwrap_or(default.initial_backoff),
+        attempt_timeout: args.attempt_timeout.unwrap_or(default.attempt_timeout),
+        timeout: args.timeout.or(default.timeout),
+        ..default
+    }
+}
+
+/// Look up the address the system's DNS resolver queries from
+///
+/// The lookup uses the same record, transport and retry policy as the WAN
+/// lookups, so the two can be compared.
+///
+/// # Arguments
+///
+/// * `args` - The parsed command-line arguments
+/// * `dns` - How DNS queries should be sent
+///
+/// # Returns
+///
+/// A future that resolves to the resolver's addresses and client subnet
+fn find_resolver_egress(
+    args: &Args,
+    dns: DnsOptions,
+) -> impl std::future::Future<Output = Result<UserIps>> + use<> {
+    let record = args
+        .wan_record
+        .clone()
+        .unwrap_or_else(|| MYADDR_RECORD.to_string());
+    let policy = retry_policy(args);
+
+    async move {
+        let lookup = policy.retry("Resolver egress lookup", || resolver_egress(&record, dns));
+        policy.deadline("Resolver egress lookup", lookup).await
+    }
+}
+
+/// Format our WAN addresses next to the addresses of the system's resolver
+///
+/// Each line is labelled so the two can be told apart, followed by the
+/// client subnet the resolver passes on, if it passes one on.
+///
+/// # Arguments
+///
+/// * `wan` - Our formatted WAN addresses
+/// * `resolver` - The resolver's formatted addresses
+/// * `client_subnet` - The client subnet the resolver passes on
+///
+/// # Returns
+///
+/// The labelled addresses, one per line
+fn format_leak_report(
+    wan: HashSet<String>,
+    resolver: HashSet<String>,
+    client_subnet: Option<ClientSubnet>,
+) -> String {
+    let mut lines = vec![];
+
+    for (label, ips) in [("wan", wan), ("resolver", resolver)] {
+        let ips = format_ips::<RandomState>(ips);
+        lines.extend(ips.lines().map(|ip| format!("{label}: {ip}")));
+    }
+    if let Some(client_subnet) = client_subnet {
+        lines.push(format!("client subnet: {client_subnet}"));
+    }
+
+    lines.join("\n")
+}
+
+/// Run the RFC 5780 NAT behaviour tests against the STUN servers from the
+/// command-line arguments, or a public server that supports them
+///
+/// # Errors
+///
+/// Returns an error if no server could run the tests, or they take longer
+/// than the timeout
+async fn find_nat_behaviour(args: &Args, dns: DnsOptions) -> Result<NatBehaviour> {
+    let servers = if args.stun_servers.is_empty() {
+        vec![nat::DEFAULT_SERVER.to_string()]
+    } else {
+        args.stun_servers.clone()
+    };
+    let strategy = if args.only_6 { Ipv6 } else { Ipv4 };
+
+    retry_policy(args)
+        .deadline(
+            "NAT type discovery",
+            nat::discover(&servers, strategy, dns.source),
+        )
+        .await
+}
+
+/// Format the results of the NAT behaviour tests, one labelled line each
+///
+/// # Arguments
+///
+/// * `behaviour` - The results of the tests
+///
+/// # Returns
+///
+/// The public address, the classification, and each behaviour
+fn format_nat_behaviour(behaviour: &NatBehaviour) -> String {
+    [
+        format!("public address: {}", behaviour.public),
+        format!("nat type: {}", behaviour.classification()),
+        format!("mapping: {}", behaviour.mapping),
+        format!("filtering: {}", behaviour.filtering),
+        format!(
+            "hairpinning: {}",
+            if behaviour.hairpinning { "yes" } else { "no" }
+        ),
+    ]
+    .join("\n")
+}
+
+/// Gather the addresses needed to tell whether there is a carrier-grade NAT
+///
+/// The local address is the one traffic to the internet leaves from. The
+/// router and the WAN providers are asked at once. A router that does
+/// not report its external address is reported on stderr, as the other
+/// evidence may still be enough.
+///
+/// # Errors
+///
+/// Returns an error if there is no IPv4 route to the internet, or the WAN
+/// address cannot be found
+async fn find_cgnat_evidence(
+    args: &Args,
+    providers: &WanProviders,
+    dns: DnsOptions,
+) -> Result<Evidence> {
+    let local = vec![egress_address(dns.source).await?];
+
+    let policy = retry_policy(args);
+    let router = policy.deadline(
+        "Router lookup",
+        router::external_ip(args.router, router::SSDP_ADDR, dns.source),
+    );
+    let wan = find_wan(providers, Ipv4, args.consensus, args.quorum);
+    let (router, wan) = futures::future::join(router, wan).await;
+
+    let router = router.inspect_err(|e| eprintln!("warning: {e}")).ok();
+    let wan = wan?
+        .iter()
+        .filter_map(|ip| match ip.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        })
+        .collect();
+
+    Ok(Evidence { local, router, wan })
+}
+
+/// Format the evidence for a carrier-grade NAT, followed by the verdict
+///
+/// # Arguments
+///
+/// * `evidence` - The local, router and WAN addresses
+/// * `verdict` - What the evidence shows
+///
+/// # Returns
+///
+/// One labelled line per address, then the verdict
+fn format_cgnat_report(evidence: &Evidence, verdict: Verdict) -> String {
+    let mut lines: Vec<_> = evidence
+        .local
+        .iter()
+        .map(|ip| format!("local: {ip}"))
+        .collect();
+    lines.push(evidence.router.map_or_else(
+        || "router: unknown".to_string(),
+        |ip| format!("router: {ip}"),
+    ));
+    lines.extend(evidence.wan.iter().map(|ip| format!("wan: {ip}")));
+    lines.push(format!("behind CGNAT: {verdict}"));
+
+    lines.join("\n")
+}
+
 /// Process command-line arguments to determine which WAN IP strategies to use
 ///
 /// This function analyzes the command-line arguments and returns a vector of futures
@@ -89,17 +563,31 @@ async fn main() -> Result<()> {
 /// # Arguments
 ///
 /// * `args` - The parsed command-line arguments
+/// * `providers` - The WAN providers to query, in order of preference
 ///
 /// # Returns
 ///
 /// A vector of futures that will resolve to IP addresses
-fn process_args(args: Args) -> Vec<impl std::future::Future<Output = Result<MyIps>>> {
+fn process_args(
+    args: &Args,
+    providers: &WanProviders,
+) -> Vec<impl std::future::Future<Output = Result<Addresses>> + use<>> {
     let mut strategies = vec![];
 
     if args.only_local {
         return strategies;
     }
 
+    let (consensus, quorum) = (args.consensus, args.quorum);
+    let find_wan_ip = |strategy| {
+        let providers = providers.clone();
+        async move {
+            find_wan(&providers, strategy, consensus, quorum)
+                .await
+                .map(addresses)
+        }
+    };
+
     if !args.only_6 {
         strategies.push(find_wan_ip(Ipv4));
     }
@@ -110,12 +598,137 @@ fn process_args(args: Args) -> Vec<impl std::future::Future<Output = Result<MyIp
     strategies
 }
 
+/// Ask the providers for the WAN address
+///
+/// # Arguments
+///
+/// * `providers` - The WAN providers to ask
+/// * `strategy` - The IP version to look up
+/// * `consensus` - Whether to ask every provider and find the address they agree on
+/// * `quorum` - How many providers must agree, defaulting to a majority
+///
+/// # Errors
+///
+/// Returns an error if no provider finds the address, or no quorum is reached
+async fn find_wan(
+    providers: &WanProviders,
+    strategy: IpVersion,
+    consensus: bool,
+    quorum: Option<usize>,
+) -> Result<MyIps> {
+    if consensus {
+        find_consensus(providers, strategy, quorum).await
+    } else {
+        providers.find_wan_ip(strategy).await
+    }
+}
+
+/// Find the WAN address each routable local address egresses as
+///
+/// Each local address is used as the source of its own WAN lookups, and
+/// they are all run at once. Addresses whose lookups fail, such as those with
+/// no route to the internet, are reported on stderr.
+///
+/// # Arguments
+///
+/// * `args` - The parsed command-line arguments
+/// * `dns` - How DNS queries should be sent
+///
+/// # Returns
+///
+/// Each local address and its interface next to its WAN address, one per line
+///
+/// # Errors
+///
+/// Returns an error if the interfaces cannot be listed, the providers cannot
+/// be configured, or the lookups fail for every local address
+async fn find_wan_ip_per_interface(args: &Args, dns: DnsOptions) -> Result<String> {
+    let version = match (args.only_4, args.only_6) {
+        (true, _) => Some(Ipv4),
+        (_, true) => Some(Ipv6),
+        _ => None,
+    };
+
+    let lookups = find_routable_local_ips(version, &interface_filter(args))?
+        .into_iter()
+        .map(|(interface, ip)| {
+            let source = SourceAddresses::from_ips([ip])?;
+            let providers = select_providers(args, DnsOptions { source, ..dns })?;
+            let strategy = if ip.is_ipv4() { Ipv4 } else { Ipv6 };
+
+            Ok(async move {
+                let wan = find_wan(&providers, strategy, args.consensus, args.quorum).await;
+                (interface, ip, wan)
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut lines = vec![];
+    for (interface, ip, wan) in join_all(lookups).await {
+        match wan {
+            Ok(wan) => lines.push(format_egress(&interface, ip, &wan)),
+            Err(e) => eprintln!("warning: no WAN address via {ip} ({interface}): {e}"),
+        }
+    }
+
+    if lines.is_empty() {
+        bail!("No local address reached a WAN provider");
+    }
+
+    Ok(lines.join("\n"))
+}
+
+/// Format a local address and its interface next to the WAN address it egresses as
+fn format_egress(interface: &str, local: IpAddr, wan: &MyIps) -> String {
+    format!("{local} ({interface}) -> {}", format_answer(wan))
+}
+
+/// Find the WAN address a quorum of providers agree on
+///
+/// Every provider is asked at once. Providers that answer with a different
+/// address from the one agreed on are reported on stderr, so that split
+/// tunnels and load-balanced NAT pools are visible rather than hidden.
+///
+/// # Arguments
+///
+/// * `providers` - The WAN providers to ask
+/// * `strategy` - The IP version to look up
+/// * `quorum` - How many providers must agree, defaulting to a majority
+///
+/// # Returns
+///
+/// The agreed address
+///
+/// # Errors
+///
+/// Returns an error listing every provider's answer if no quorum was reached
+async fn find_consensus(
+    providers: &WanProviders,
+    strategy: IpVersion,
+    quorum: Option<usize>,
+) -> Result<MyIps> {
+    let consensus = providers.consensus(strategy, quorum).await;
+
+    if let Some(agreed) = consensus.agreed {
+        for (provider, ips) in consensus.dissenters() {
+            eprintln!(
+                "warning: {provider} disagreed with {agreed}, and reported {}",
+                format_answer(ips)
+            );
+        }
+    }
+
+    consensus.into_result()
+}
+
 /// Get local IPs based on command-line arguments
 ///
 /// This function returns a vector of results containing local IP addresses
 /// based on the command-line arguments. The IPs returned depend on:
 /// - Whether WAN-only mode is enabled
 /// - Whether IPv4-only or IPv6-only mode is enabled
+/// - Which network interfaces are included or excluded
+/// - Whether temporary or otherwise unstable IPv6 addresses are left out
 ///
 /// # Arguments
 ///
@@ -124,26 +737,35 @@ fn process_args(args: Args) -> Vec<impl std::future::Future<Output = Result<MyIp
 /// # Returns
 ///
 /// A vector of results containing local IP addresses
-fn get_local_ips(args: Args) -> Vec<Result<MyIps>> {
+fn get_local_ips(args: &Args) -> Vec<Result<Addresses>> {
+    let interfaces = interface_filter(args);
+
+    // Only netlink reports the flags, so elsewhere nothing would be left out
+    if cfg!(not(target_os = "linux")) && (args.no_temporary || args.only_stable) {
+        eprintln!(
+            "warning: IPv6 address flags can't be read on this platform, so --no-temporary and --only-stable have no effect"
+        );
+    }
+
     match args {
         Args {
             only_wan: false,
             only_6: false,
             only_4: true,
             ..
-        } => vec![(find_local_ip(Some(Ipv4)))],
+        } => vec![(find_local_ip_with(Some(Ipv4), &interfaces))],
         Args {
             only_wan: false,
             only_6: true,
             only_4: false,
             ..
-        } => vec![(find_local_ip(Some(Ipv6)))],
+        } => vec![(find_local_ip_with(Some(Ipv6), &interfaces))],
         Args {
             only_wan: false,
             only_6: false,
             only_4: false,
             ..
-        } => vec![(find_local_ip(None))],
+        } => vec![(find_local_ip_with(None, &interfaces))],
         _ => vec![],
     }
 }
@@ -153,7 +775,9 @@ mod tests {
     use super::*;
     use miette::miette;
     use std::net::IpAddr;
+    use whatismyip::find_local_ip;
     use whatismyip::myip::MyIp;
+    use whatismyip::nat::Behaviour;
 
     type TestResult = Result<()>;
 
@@ -204,16 +828,59 @@ mod tests {
     }
 
     #[test]
-    fn test_process_args_default() -> TestResult {
+    fn test_select_providers_defaults_to_the_dns_providers() -> TestResult {
+        let providers = select_providers(&Args::default(), DnsOptions::default())?;
+        let names: Vec<_> = providers.names().collect();
+
+        if names != WanProviders::DEFAULT {
+            return Err(miette!(
+                "Expected {:?}, got {:?}",
+                WanProviders::DEFAULT,
+                names
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_providers_with_a_proxy() -> TestResult {
         let args = Args {
-            only_local: false,
-            only_wan: false,
-            only_4: false,
-            only_6: false,
-            reverse: false,
+            proxy: Some("socks5h://127.0.0.1:9050".to_string()),
+            ..Args::default()
+        };
+
+        let providers = select_providers(&args, DnsOptions::default())?;
+        let names: Vec<_> = providers.names().collect();
+
+        // Only the http provider can go through the proxy
+        if names != vec![Http::NAME] {
+            return Err(miette!("Expected only the http provider, got {:?}", names));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_providers_rejects_providers_that_cannot_use_the_proxy() -> TestResult {
+        let args = Args {
+            proxy: Some("http://proxy.example.com:3128".to_string()),
+            providers: vec![Http::NAME.to_string(), Google::NAME.to_string()],
+            ..Args::default()
         };
 
-        let strategies = process_args(args);
+        if select_providers(&args, DnsOptions::default()).is_ok() {
+            return Err(miette!("Expected the google provider to be rejected"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_args_default() -> TestResult {
+        let args = Args::default();
+
+        let strategies = process_args(&args, &WanProviders::default());
 
         // Should have 2 strategies (IPv4 and IPv6 WAN)
         if strategies.len() != 2 {
@@ -227,13 +894,10 @@ mod tests {
     fn test_process_args_only_local() -> TestResult {
         let args = Args {
             only_local: true,
-            only_wan: false,
-            only_4: false,
-            only_6: false,
-            reverse: false,
+            ..Args::default()
         };
 
-        let strategies = process_args(args);
+        let strategies = process_args(&args, &WanProviders::default());
 
         // Should have 0 strategies (no WAN lookups)
         if !strategies.is_empty() {
@@ -246,14 +910,11 @@ mod tests {
     #[test]
     fn test_process_args_only_ipv4() -> TestResult {
         let args = Args {
-            only_local: false,
-            only_wan: false,
             only_4: true,
-            only_6: false,
-            reverse: false,
+            ..Args::default()
         };
 
-        let strategies = process_args(args);
+        let strategies = process_args(&args, &WanProviders::default());
 
         // Should have 1 strategy (IPv4 WAN only)
         if strategies.len() != 1 {
@@ -266,14 +927,11 @@ mod tests {
     #[test]
     fn test_process_args_only_ipv6() -> TestResult {
         let args = Args {
-            only_local: false,
-            only_wan: false,
-            only_4: false,
             only_6: true,
-            reverse: false,
+            ..Args::default()
         };
 
-        let strategies = process_args(args);
+        let strategies = process_args(&args, &WanProviders::default());
 
         // Should have 1 strategy (IPv6 WAN only)
         if strategies.len() != 1 {
@@ -284,16 +942,187 @@ mod tests {
     }
 
     #[test]
-    fn test_get_local_ips_default() -> TestResult {
-        let args = Args {
-            only_local: false,
-            only_wan: false,
-            only_4: false,
-            only_6: false,
-            reverse: false,
+    fn test_format_egress() -> TestResult {
+        let actual = format_egress(
+            "eth1",
+            IpAddr::from([192, 168, 2, 10]),
+            &vec![MyIp::new_plain(IpAddr::from([198, 51, 100, 20]))],
+        );
+        let expected = "192.168.2.10 (eth1) -> 198.51.100.20";
+
+        if actual != expected {
+            return Err(miette!("Expected '{}', got '{}'", expected, actual));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_leak_report() -> TestResult {
+        let actual = format_leak_report(
+            HashSet::from(["207.105.7.192".to_string(), "2001:db8::1".to_string()]),
+            HashSet::from(["172.253.2.5".to_string()]),
+            Some("207.105.7.0/24".parse()?),
+        );
+        let expected = "wan: 2001:db8::1\nwan: 207.105.7.192\nresolver: 172.253.2.5\nclient subnet: 207.105.7.0/24";
+
+        if actual != expected {
+            return Err(miette!("Expected '{}', got '{}'", expected, actual));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_cidr() -> TestResult {
+        let ipv4 = Address::new(MyIp::new_plain(IpAddr::from([192, 168, 1, 56])))
+            .with_interface("wlan0")
+            .with_prefix_len(24);
+        let ipv6 = Address::new(MyIp::new_plain("2001:db8::5".parse().into_diagnostic()?))
+            .with_interface("wlan0")
+            .with_prefix_len(64);
+        let wan = Address::new(MyIp::new_plain(IpAddr::from([207, 105, 7, 192])));
+
+        for (ip, cidr, expected) in [
+            (&ipv4, Cidr::Address, "192.168.1.56/24 (wlan0)"),
+            (
+                &ipv4,
+                Cidr::Network,
+                "192.168.1.0/24 (wlan0, broadcast 192.168.1.255)",
+            ),
+            (&ipv6, Cidr::Address, "2001:db8::5/64 (wlan0)"),
+            (&ipv6, Cidr::Network, "2001:db8::/64 (wlan0)"),
+            (&wan, Cidr::Network, "207.105.7.192"),
+        ] {
+            let actual = format_cidr(ip, cidr);
+            if actual != expected {
+                return Err(miette!("Expected '{}', got '{}'", expected, actual));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_scopes() -> TestResult {
+        let ips = vec![
+            Address::new(MyIp::new_plain(IpAddr::from([207, 105, 7, 192]))),
+            Address::new(MyIp::new_plain(IpAddr::from([192, 168, 1, 56]))).with_interface("wlan0"),
+            Address::new(MyIp::new_plain(IpAddr::from([127, 0, 0, 1]))).with_interface("lo"),
+        ];
+
+        let actual = filter_scopes(vec![Ok(ips.clone())], &[Scope::Global, Scope::Private])?
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        if actual != vec![ips[..2].to_vec()] {
+            return Err(miette!(
+                "Expected the global and private addresses, got {actual:?}"
+            ));
+        }
+
+        let actual = filter_scopes(vec![Ok(ips.clone())], &[])?
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+        if actual != vec![ips.clone()] {
+            return Err(miette!("Expected every address, got {actual:?}"));
+        }
+
+        if filter_scopes(vec![Ok(ips)], &[Scope::Shared]).is_ok() {
+            return Err(miette!("Expected no addresses in scope to be an error"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_scopes_without_scopes_allows_no_addresses() -> TestResult {
+        let actual = filter_scopes(vec![Ok(vec![])], &[])?
+            .into_iter()
+            .collect::<Result<Vec<_>>>()?;
+
+        if actual != vec![Addresses::new()] {
+            return Err(miette!("Expected an empty result, got {actual:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_interfaces() -> TestResult {
+        let wan = Address::new(MyIp::new_plain("207.105.7.192".parse().into_diagnostic()?));
+        let local = Address::new(MyIp::new_plain("192.168.1.56".parse().into_diagnostic()?))
+            .with_interface("wlan0");
+
+        let actual = strip_interfaces(vec![Ok(vec![wan]), Ok(vec![local])])
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|ip| ip.to_string())
+            .collect::<Vec<_>>();
+
+        if actual != ["207.105.7.192", "192.168.1.56"] {
+            return Err(miette!("Expected bare addresses, got {actual:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_bootstrap_warning() -> TestResult {
+        let actual = format_bootstrap_warning(&BootstrapFallback {
+            host: "ns1.google.com".to_string(),
+            ip: "216.239.32.10".parse().into_diagnostic()?,
+            reason: "no answer within 2s".to_string(),
+        });
+        let expected = "warning: the system resolver could not look up ns1.google.com (no answer within 2s), so its built-in address 216.239.32.10 was used";
+
+        if actual != expected {
+            return Err(miette!("Expected '{}', got '{}'", expected, actual));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_cgnat_report() -> TestResult {
+        let evidence = Evidence {
+            local: vec!["192.168.1.56".parse().into_diagnostic()?],
+            router: Some("100.72.3.4".parse().into_diagnostic()?),
+            wan: vec!["207.105.7.192".parse().into_diagnostic()?],
         };
+        let actual = format_cgnat_report(&evidence, evidence.verdict());
+        let expected = "local: 192.168.1.56\nrouter: 100.72.3.4\nwan: 207.105.7.192\nbehind CGNAT: yes, the router's external address 100.72.3.4 is not public";
+
+        if actual != expected {
+            return Err(miette!("Expected '{}', got '{}'", expected, actual));
+        }
 
-        let local_ips = get_local_ips(args);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format_nat_behaviour() -> TestResult {
+        let actual = format_nat_behaviour(&NatBehaviour {
+            local: "192.168.1.56:40000".parse().into_diagnostic()?,
+            public: "207.105.7.192:40000".parse().into_diagnostic()?,
+            mapping: Behaviour::EndpointIndependent,
+            filtering: Behaviour::AddressAndPortDependent,
+            hairpinning: true,
+        });
+        let expected = "public address: 207.105.7.192:40000\nnat type: port-restricted cone\nmapping: endpoint-independent\nfiltering: address and port-dependent\nhairpinning: yes";
+
+        if actual != expected {
+            return Err(miette!("Expected '{}', got '{}'", expected, actual));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_local_ips_default() -> TestResult {
+        let args = Args::default();
+
+        let local_ips = get_local_ips(&args);
 
         // Should have 1 result (all local IPs)
         if local_ips.len() != 1 {
@@ -311,14 +1140,11 @@ mod tests {
     #[test]
     fn test_get_local_ips_only_wan() -> TestResult {
         let args = Args {
-            only_local: false,
             only_wan: true,
-            only_4: false,
-            only_6: false,
-            reverse: false,
+            ..Args::default()
         };
 
-        let local_ips = get_local_ips(args);
+        let local_ips = get_local_ips(&args);
 
         // Should have 0 results (no local IPs)
         if !local_ips.is_empty() {
@@ -331,14 +1157,11 @@ mod tests {
     #[test]
     fn test_get_local_ips_only_ipv4() -> TestResult {
         let args = Args {
-            only_local: false,
-            only_wan: false,
             only_4: true,
-            only_6: false,
-            reverse: false,
+            ..Args::default()
         };
 
-        let local_ips = get_local_ips(args);
+        let local_ips = get_local_ips(&args);
 
         // Should have 1 result (IPv4 local IPs)
         if local_ips.len() != 1 {
@@ -363,14 +1186,11 @@ mod tests {
     #[test]
     fn test_get_local_ips_only_ipv6() -> TestResult {
         let args = Args {
-            only_local: false,
-            only_wan: false,
-            only_4: false,
             only_6: true,
-            reverse: false,
+            ..Args::default()
         };
 
-        let local_ips = get_local_ips(args);
+        let local_ips = get_local_ips(&args);
 
         // Should have 1 result (IPv6 local IPs)
         if local_ips.len() != 1 {
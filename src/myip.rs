@@ -6,7 +6,10 @@
 //! ## Core Types
 //!
 //! - `MyIp`: An enum representing either a plain IP address or an IP with reverse DNS information
+//! - `Address`: A `MyIp` along with the network interface, prefix length and
+//!   flags of local addresses
 //! - `ReversedIp`: A wrapper around a hostname string obtained from reverse DNS lookup
+//! - `ClientSubnet`: The subnet a recursive resolver passes on to nameservers using EDNS
 //!
 //! ## Features
 //!
@@ -15,9 +18,13 @@
 //! - Formatting functionality for displaying IP addresses with optional hostname information
 //! - Conversion utilities for working with standard Rust IP address types
 
+use crate::interface::{AddressFlags, InterfaceAddress};
+use crate::scope::Scope;
 use core::fmt;
 use core::fmt::{Display, Formatter};
-use std::net::IpAddr;
+use miette::{Result, miette};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
 /// Represents an IP address with optional reverse DNS information
 ///
@@ -88,6 +95,242 @@ impl MyIp {
             Self::Reversed { ip, .. } | Self::Plain { ip } => *ip,
         }
     }
+
+    /// Get the scope of the IP address, such as private or global
+    ///
+    /// # Returns
+    ///
+    /// The special-purpose range the address is in, or `Scope::Global`
+    #[must_use]
+    pub const fn scope(&self) -> Scope {
+        Scope::of(self.ip())
+    }
+}
+
+/// An IP address along with what is known about where it was found
+///
+/// Local addresses carry the name of the network interface they were found
+/// on, so that they can be told apart on hosts with many interfaces, and the
+/// length of their on-link prefix and their IPv6 address flags where the
+/// platform reports them. WAN addresses carry none of these.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Address {
+    ip: MyIp,
+    interface: Option<String>,
+    prefix_len: Option<u8>,
+    flags: Option<AddressFlags>,
+}
+
+impl Address {
+    /// Create an address that nothing else is known about
+    ///
+    /// # Arguments
+    ///
+    /// * `ip` - The IP address, with any reverse DNS entry
+    ///
+    /// # Returns
+    ///
+    /// A new `Address` with no interface, prefix length or flags
+    #[must_use]
+    pub const fn new(ip: MyIp) -> Self {
+        Self {
+            ip,
+            interface: None,
+            prefix_len: None,
+            flags: None,
+        }
+    }
+
+    /// Attach the name of the network interface the address belongs to
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The interface name, such as `wlan0`
+    ///
+    /// # Returns
+    ///
+    /// The same address, labelled with the interface
+    #[must_use]
+    pub fn with_interface(mut self, name: impl Into<String>) -> Self {
+        self.interface = Some(name.into());
+        self
+    }
+
+    /// Drop the name of the network interface the address belongs to
+    ///
+    /// # Returns
+    ///
+    /// The same address, printed without an interface label
+    #[must_use]
+    pub fn without_interface(mut self) -> Self {
+        self.interface = None;
+        self
+    }
+
+    /// Attach the length of the address's on-link prefix
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - How many leading bits of the address are on-link, such as 24
+    ///
+    /// # Returns
+    ///
+    /// The same address, with its prefix length
+    #[must_use]
+    pub const fn with_prefix_len(mut self, len: u8) -> Self {
+        self.prefix_len = Some(len);
+        self
+    }
+
+    /// Attach the kernel's flags for the address
+    ///
+    /// # Arguments
+    ///
+    /// * `flags` - What the kernel says about the address
+    ///
+    /// # Returns
+    ///
+    /// The same address, with its flags
+    #[must_use]
+    pub const fn with_flags(mut self, flags: AddressFlags) -> Self {
+        self.flags = Some(flags);
+        self
+    }
+
+    /// Attach a reverse DNS entry, keeping the interface, prefix length and flags
+    ///
+    /// # Arguments
+    ///
+    /// * `reversed` - The reverse DNS entry for the IP address
+    ///
+    /// # Returns
+    ///
+    /// The same address, with the reverse DNS entry
+    #[must_use]
+    pub fn with_reversed(mut self, reversed: ReversedIp) -> Self {
+        self.ip = MyIp::new_reversed(self.ip.ip(), reversed);
+        self
+    }
+
+    /// Get the IP address, with any reverse DNS entry
+    #[must_use]
+    pub const fn my_ip(&self) -> &MyIp {
+        &self.ip
+    }
+
+    /// Get the IP address
+    #[must_use]
+    pub const fn ip(&self) -> IpAddr {
+        self.ip.ip()
+    }
+
+    /// Get the scope of the IP address, such as private or global
+    #[must_use]
+    pub const fn scope(&self) -> Scope {
+        self.ip.scope()
+    }
+
+    /// Get the name of the network interface the address belongs to
+    ///
+    /// # Returns
+    ///
+    /// The interface name for local addresses, or `None` for WAN addresses
+    #[must_use]
+    pub fn interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+
+    /// Get the length of the address's on-link prefix
+    ///
+    /// # Returns
+    ///
+    /// The prefix length for local addresses where the platform reports it,
+    /// or `None` for WAN addresses
+    #[must_use]
+    pub const fn prefix_len(&self) -> Option<u8> {
+        self.prefix_len
+    }
+
+    /// Get what the kernel says about the address
+    ///
+    /// # Returns
+    ///
+    /// The flags for local IPv6 addresses on Linux, or `None` otherwise
+    #[must_use]
+    pub const fn flags(&self) -> Option<AddressFlags> {
+        self.flags
+    }
+
+    /// Get the network the address is on, such as `192.168.1.0` for
+    /// `192.168.1.56/24`
+    ///
+    /// # Returns
+    ///
+    /// The address with every bit after the prefix cleared, or `None` if the
+    /// prefix length is unknown
+    #[must_use]
+    pub fn network(&self) -> Option<IpAddr> {
+        let prefix_len = u32::from(self.prefix_len?);
+
+        Some(match self.ip() {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from_bits(ip.to_bits() & mask))
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX.checked_shl(128 - prefix_len).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & mask))
+            }
+        })
+    }
+
+    /// Get the broadcast address of the network the address is on, such as
+    /// `192.168.1.255` for `192.168.1.56/24`
+    ///
+    /// # Returns
+    ///
+    /// The address with every bit after the prefix set, or `None` if the
+    /// prefix length is unknown, the address is IPv6, which has no broadcast,
+    /// or the network is a `/31` or `/32`, which have none (RFC 3021)
+    #[must_use]
+    pub fn broadcast(&self) -> Option<IpAddr> {
+        let prefix_len = u32::from(self.prefix_len?);
+
+        match self.ip() {
+            IpAddr::V4(ip) if prefix_len < 31 => {
+                let host_mask = u32::MAX >> prefix_len;
+                Some(IpAddr::V4(Ipv4Addr::from_bits(ip.to_bits() | host_mask)))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<MyIp> for Address {
+    /// Create an address that nothing else is known about
+    fn from(ip: MyIp) -> Self {
+        Self::new(ip)
+    }
+}
+
+impl From<Address> for MyIp {
+    /// Drop everything but the IP address and any reverse DNS entry
+    fn from(address: Address) -> Self {
+        address.ip
+    }
+}
+
+impl From<InterfaceAddress> for Address {
+    /// Create a plain IP address labelled with its interface, prefix length
+    /// and flags
+    fn from(address: InterfaceAddress) -> Self {
+        Self {
+            ip: MyIp::new_plain(address.ip),
+            interface: Some(address.name),
+            prefix_len: address.prefix_len,
+            flags: address.flags,
+        }
+    }
 }
 
 /// Represents a hostname obtained from reverse DNS lookup
@@ -139,11 +382,92 @@ impl Display for MyIp {
     }
 }
 
+impl Display for Address {
+    /// Format an address for display
+    ///
+    /// Addresses without an interface are displayed as their `MyIp` is.
+    /// Local addresses have their interface name in front of any hostname,
+    /// as "`ip_address` (interface)" or "`ip_address` (interface, hostname)".
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match (&self.ip, &self.interface) {
+            (ip, None) => write!(f, "{ip}"),
+            (MyIp::Reversed { ip, reversed }, Some(interface)) => {
+                write!(f, "{ip} ({interface}, {})", reversed.0)
+            }
+            (MyIp::Plain { ip }, Some(interface)) => write!(f, "{ip} ({interface})"),
+        }
+    }
+}
+
+/// The subnet a recursive resolver passes on to nameservers using EDNS
+///
+/// Resolvers that support EDNS Client Subnet (RFC 7871) tell the nameservers
+/// they ask which subnet the query came from, so that CDNs can answer with a
+/// nearby server. Google's `o-o.myaddr.l.google.com` record reports it as a
+/// TXT string such as `edns0-client-subnet 203.0.113.0/24`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ClientSubnet {
+    /// The network address of the subnet
+    pub ip: IpAddr,
+    /// How many leading bits of the address identify the subnet
+    pub prefix_len: u8,
+}
+
+impl ClientSubnet {
+    /// The prefix Google puts in front of the subnet in its TXT answer
+    pub const TXT_PREFIX: &'static str = "edns0-client-subnet";
+
+    /// Read the subnet from a TXT answer such as `edns0-client-subnet 203.0.113.0/24`
+    ///
+    /// # Returns
+    ///
+    /// The subnet, or `None` if the text is not a client subnet answer
+    #[must_use]
+    pub fn from_txt(txt: &str) -> Option<Self> {
+        txt.strip_prefix(Self::TXT_PREFIX)?.trim().parse().ok()
+    }
+}
+
+impl FromStr for ClientSubnet {
+    type Err = miette::Report;
+
+    /// Parse a subnet in CIDR notation, such as `203.0.113.0/24`
+    fn from_str(subnet: &str) -> Result<Self> {
+        let (ip, prefix_len) = subnet
+            .split_once('/')
+            .ok_or_else(|| miette!("Client subnet '{subnet}' has no prefix length"))?;
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| miette!("Client subnet '{subnet}' has an invalid address"))?;
+        let prefix_len: u8 = prefix_len
+            .parse()
+            .map_err(|_| miette!("Client subnet '{subnet}' has an invalid prefix length"))?;
+
+        let max_len = if ip.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(miette!(
+                "Client subnet '{subnet}' has a prefix longer than {max_len} bits"
+            ));
+        }
+
+        Ok(Self { ip, prefix_len })
+    }
+}
+
+impl Display for ClientSubnet {
+    /// Format the subnet in CIDR notation, such as `203.0.113.0/24`
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.ip, self.prefix_len)
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use miette::{Result, miette};
+    use miette::{IntoDiagnostic, Result, miette};
     use std::net::{IpAddr, Ipv4Addr};
 
+    use super::Address;
+    use super::ClientSubnet;
     use super::MyIp;
     use super::ReversedIp;
 
@@ -192,6 +516,79 @@ mod tests {
         }
         Ok(())
     }
+    #[test]
+    fn can_format_an_ip_with_its_interface() -> TestResult {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 56));
+        let plain = Address::new(MyIp::new_plain(ip)).with_interface("wlan0");
+        let reversed = plain.clone().with_reversed("laptop.lan".to_string().into());
+
+        for (actual, expected) in [
+            (plain.to_string(), "192.168.1.56 (wlan0)"),
+            (reversed.to_string(), "192.168.1.56 (wlan0, laptop.lan)"),
+        ] {
+            if actual != expected {
+                return Err(miette!("Expected '{}', got '{}'", expected, actual));
+            }
+        }
+        if reversed.interface() != Some("wlan0") {
+            return Err(miette!(
+                "Expected the interface to be kept, got {reversed:?}"
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn can_drop_the_interface() -> TestResult {
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 56));
+        let bare = Address::new(MyIp::new_plain(ip))
+            .with_interface("wlan0")
+            .with_prefix_len(24)
+            .without_interface();
+
+        if bare.to_string() != "192.168.1.56" || bare.prefix_len() != Some(24) {
+            return Err(miette!(
+                "Expected only the interface to be dropped, got {bare:?}"
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn can_derive_the_network_and_broadcast() -> TestResult {
+        for (ip, prefix_len, network, broadcast) in [
+            ("192.168.1.56", 24, "192.168.1.0", Some("192.168.1.255")),
+            ("10.20.30.40", 0, "0.0.0.0", Some("255.255.255.255")),
+            ("198.51.100.9", 31, "198.51.100.8", None),
+            ("198.51.100.9", 32, "198.51.100.9", None),
+            ("2001:db8::5", 64, "2001:db8::", None),
+            ("2001:db8::5", 128, "2001:db8::5", None),
+        ] {
+            let my_ip = Address::new(MyIp::new_plain(ip.parse().into_diagnostic()?))
+                .with_prefix_len(prefix_len);
+            let expected_network: IpAddr = network.parse().into_diagnostic()?;
+            let expected_broadcast: Option<IpAddr> =
+                broadcast.map(str::parse).transpose().into_diagnostic()?;
+
+            if my_ip.network() != Some(expected_network) || my_ip.broadcast() != expected_broadcast
+            {
+                return Err(miette!(
+                    "Expected {network} and {broadcast:?} for {ip}/{prefix_len}, got {:?} and {:?}",
+                    my_ip.network(),
+                    my_ip.broadcast()
+                ));
+            }
+        }
+
+        if Address::new(MyIp::new_plain(IpAddr::V4(Ipv4Addr::LOCALHOST)))
+            .network()
+            .is_some()
+        {
+            return Err(miette!("Expected no network without a prefix length"));
+        }
+        Ok(())
+    }
+
     #[test]
     fn can_get_the_ip_v4() -> TestResult {
         let actual = MyIp::new_plain(IpAddr::V4(Ipv4Addr::LOCALHOST));
@@ -215,4 +612,44 @@ mod tests {
         }
         Ok(())
     }
+
+    #[test]
+    fn can_read_a_client_subnet_from_a_txt_answer() -> TestResult {
+        let actual = ClientSubnet::from_txt("edns0-client-subnet 203.0.113.0/24");
+        let expected = Some(ClientSubnet {
+            ip: IpAddr::V4(Ipv4Addr::new(203, 0, 113, 0)),
+            prefix_len: 24,
+        });
+
+        if actual != expected {
+            return Err(miette!("Expected {:?}, got {:?}", expected, actual));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_txt_answers_that_are_not_client_subnets() -> TestResult {
+        for txt in [
+            "203.0.113.7",
+            "edns0-client-subnet",
+            "edns0-client-subnet 203.0.113.0",
+            "edns0-client-subnet 203.0.113.0/33",
+            "edns0-client-subnet 2001:db8::/129",
+        ] {
+            if let Some(subnet) = ClientSubnet::from_txt(txt) {
+                return Err(miette!("Expected '{}' to be ignored, got {}", txt, subnet));
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn can_format_a_client_subnet() -> TestResult {
+        let actual = "2001:db8:1234::/48".parse::<ClientSubnet>()?.to_string();
+
+        if actual != "2001:db8:1234::/48" {
+            return Err(miette!("Expected '2001:db8:1234::/48', got '{}'", actual));
+        }
+        Ok(())
+    }
 }
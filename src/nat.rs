@@ -0,0 +1,549 @@
+//! Discovering how a NAT behaves, using STUN (RFC 5780)
+//!
+//! Whether two peers can talk to each other directly depends on how the NATs
+//! between them map and filter UDP traffic. RFC 5780 describes a series of
+//! Binding Requests that tell these behaviours apart, using a STUN server
+//! that can answer from a second IP address and port.
+//!
+//! ## Tests
+//!
+//! - Mapping: whether the public address and port stay the same when the
+//!   same socket talks to a different server address or port
+//! - Filtering: whether responses from a different server address or port
+//!   are let back in
+//! - Hairpinning: whether traffic sent to our own public address comes back
+//!   to us
+//!
+//! Behaviours are named as in RFC 4787, and also given the older "cone"
+//! names that support teams tend to ask for.
+
+use crate::stun::{self, ChangeRequest, MAX_TRANSMISSIONS, new_transaction_id};
+use crate::{IpVersion, SourceAddresses};
+use core::fmt;
+use core::fmt::{Display, Formatter};
+use miette::{IntoDiagnostic, Result, bail, miette};
+use std::net::SocketAddr;
+use tokio::net::UdpSocket;
+
+/// A public STUN server that supports RFC 5780, used when no other is given
+pub const DEFAULT_SERVER: &str = "stun.stunprotocol.org:3478";
+
+/// How many times a request that a NAT may filter out is sent before
+/// deciding it was filtered
+const PROBE_TRANSMISSIONS: u32 = 3;
+
+/// How a NAT maps or filters traffic, depending on where it goes to or
+/// comes from
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Behaviour {
+    /// The same for every remote address and port
+    EndpointIndependent,
+    /// Depends on the remote IP address, but not the port
+    AddressDependent,
+    /// Depends on both the remote IP address and port
+    AddressAndPortDependent,
+}
+
+impl Display for Behaviour {
+    /// Format the behaviour using the names from RFC 4787
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::EndpointIndependent => write!(f, "endpoint-independent"),
+            Self::AddressDependent => write!(f, "address-dependent"),
+            Self::AddressAndPortDependent => write!(f, "address and port-dependent"),
+        }
+    }
+}
+
+/// The results of the RFC 5780 behaviour discovery tests
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct NatBehaviour {
+    /// The local address and port the tests were sent from
+    pub local: SocketAddr,
+    /// The public address and port the STUN server saw
+    pub public: SocketAddr,
+    /// How the NAT picks the public address and port
+    pub mapping: Behaviour,
+    /// Which incoming traffic the NAT lets through to the mapping
+    pub filtering: Behaviour,
+    /// Whether traffic sent to our own public address reaches us
+    pub hairpinning: bool,
+}
+
+impl NatBehaviour {
+    /// Whether there is a NAT at all, rather than the local address being
+    /// public
+    #[must_use]
+    pub fn is_nat(&self) -> bool {
+        self.local != self.public
+    }
+
+    /// A short name for the combination of behaviours, such as "full cone"
+    #[must_use]
+    pub fn classification(&self) -> &'static str {
+        match (self.is_nat(), self.mapping, self.filtering) {
+            (false, _, Behaviour::EndpointIndependent) => "open internet",
+            (false, _, _) => "no NAT, behind a firewall",
+            (true, Behaviour::EndpointIndependent, Behaviour::EndpointIndependent) => "full cone",
+            (true, Behaviour::EndpointIndependent, Behaviour::AddressDependent) => {
+                "restricted cone"
+            }
+            (true, Behaviour::EndpointIndependent, Behaviour::AddressAndPortDependent) => {
+                "port-restricted cone"
+            }
+            (true, _, _) => "symmetric",
+        }
+    }
+}
+
+/// Run the behaviour discovery tests against the first server that supports
+/// them
+///
+/// # Arguments
+///
+/// * `servers` - The STUN servers to try, as `host:port`
+/// * `strategy` - The IP version to test
+/// * `source` - The local addresses to send the tests from
+///
+/// # Errors
+///
+/// Returns an error listing why each server failed if none of them could
+/// run the tests
+pub async fn discover(
+    servers: &[String],
+    strategy: IpVersion,
+    source: SourceAddresses,
+) -> Result<NatBehaviour> {
+    let mut errors = Vec::new();
+
+    // The tests depend on what earlier ones found, so servers are tried in turn
+    for server in servers {
+        let addrs = match stun::resolve(server, strategy).await {
+            Ok(addrs) if addrs.is_empty() => {
+                errors.push(format!("{server} has no {strategy:?} address"));
+                continue;
+            }
+            Ok(addrs) => addrs,
+            Err(e) => {
+                errors.push(format!("{server}: {e}"));
+                continue;
+            }
+        };
+
+        for addr in addrs {
+            // Each run holds several receive buffers, so keep it off the caller's stack
+            match Box::pin(discover_at(addr, source)).await {
+                Ok(behaviour) => return Ok(behaviour),
+                Err(e) => errors.push(format!("{server}: {e}")),
+            }
+        }
+    }
+
+    bail!(
+        "Could not discover the NAT's behaviour ({})",
+        errors.join("; ")
+    )
+}
+
+/// Run the behaviour discovery tests against one server
+///
+/// # Errors
+///
+/// Returns an error if the server does not answer, does not support RFC 5780,
+/// or ignores requests to answer from its other address
+pub async fn discover_at(server: SocketAddr, source: SourceAddresses) -> Result<NatBehaviour> {
+    let (behaviour, hairpinning) = futures::future::join(
+        mapping_and_filtering(server, source),
+        hairpinning(server, source),
+    )
+    .await;
+    let (local, public, mapping, filtering) = behaviour?;
+
+    Ok(NatBehaviour {
+        local,
+        public,
+        mapping,
+        filtering,
+        hairpinning: hairpinning?,
+    })
+}
+
+/// Run the mapping and filtering tests, which must share one socket
+async fn mapping_and_filtering(
+    server: SocketAddr,
+    source: SourceAddresses,
+) -> Result<(SocketAddr, SocketAddr, Behaviour, Behaviour)> {
+    let socket = UdpSocket::bind(source.local_addr(server.ip())?)
+        .await
+        .into_diagnostic()?;
+    let local = local_addr(&socket, server).await?;
+
+    let first = stun::transact(&socket, server).await?;
+    let public = first
+        .mapped_address
+        .ok_or_else(|| miette!("{server} did not include a mapped address in its response"))?;
+    let other = first
+        .other_address
+        .ok_or_else(|| miette!("{server} does not support NAT behaviour discovery"))?;
+    if other.ip() == server.ip() || other.port() == server.port() {
+        bail!("{server} gave {other} as its other address, which it can't answer from");
+    }
+
+    // Without a NAT the mapping never changes
+    let mapping = if public == local {
+        Behaviour::EndpointIndependent
+    } else {
+        let second = mapped_address(&socket, SocketAddr::new(other.ip(), server.port())).await?;
+        if second == public {
+            Behaviour::EndpointIndependent
+        } else if mapped_address(&socket, other).await? == second {
+            Behaviour::AddressDependent
+        } else {
+            Behaviour::AddressAndPortDependent
+        }
+    };
+
+    let change_both = ChangeRequest {
+        ip: true,
+        port: true,
+    };
+    let change_port = ChangeRequest {
+        ip: false,
+        port: true,
+    };
+    let filtering = if let_through(&socket, server, change_both, other).await? {
+        Behaviour::EndpointIndependent
+    } else if let_through(
+        &socket,
+        server,
+        change_port,
+        SocketAddr::new(server.ip(), other.port()),
+    )
+    .await?
+    {
+        Behaviour::AddressDependent
+    } else {
+        Behaviour::AddressAndPortDependent
+    };
+
+    Ok((local, public, mapping, filtering))
+}
+
+/// Send a plain Binding Request and return the mapped address
+async fn mapped_address(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr> {
+    stun::transact(socket, server)
+        .await?
+        .mapped_address
+        .ok_or_else(|| miette!("{server} did not include a mapped address in its response"))
+}
+
+/// Ask the server to answer from `expected`, and report whether the answer
+/// got through
+async fn let_through(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change: ChangeRequest,
+    expected: SocketAddr,
+) -> Result<bool> {
+    match stun::transact_with(socket, server, change, PROBE_TRANSMISSIONS).await? {
+        None => Ok(false),
+        Some((_, from)) if from == expected => Ok(true),
+        Some((_, from)) => {
+            bail!("{server} was asked to answer from {expected}, but answered from {from}")
+        }
+    }
+}
+
+/// Send a Binding Request to our own public address from a second socket,
+/// and report whether it arrives
+async fn hairpinning(server: SocketAddr, source: SourceAddresses) -> Result<bool> {
+    let local = source.local_addr(server.ip())?;
+    let receiver = UdpSocket::bind(local).await.into_diagnostic()?;
+    let sender = UdpSocket::bind(local).await.into_diagnostic()?;
+    let public = mapped_address(&receiver, server).await?;
+
+    let request = stun::encode_binding_request(&new_transaction_id());
+    let mut buffer = [0; 1500];
+    for _ in 0..MAX_TRANSMISSIONS {
+        sender.send_to(&request, public).await.into_diagnostic()?;
+
+        let deadline = tokio::time::Instant::now() + stun::INITIAL_RTO;
+        while let Ok(received) =
+            tokio::time::timeout_at(deadline, receiver.recv_from(&mut buffer)).await
+        {
+            let (len, _) = received.into_diagnostic()?;
+            if buffer[..len] == request {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// The local address a socket sends to `server` from
+///
+/// A socket bound to the unspecified address only picks a source address
+/// when it sends, so a second socket is connected to find out which one the
+/// routing table chooses.
+async fn local_addr(socket: &UdpSocket, server: SocketAddr) -> Result<SocketAddr> {
+    let bound = socket.local_addr().into_diagnostic()?;
+    if !bound.ip().is_unspecified() {
+        return Ok(bound);
+    }
+
+    let probe = UdpSocket::bind(SocketAddr::new(bound.ip(), 0))
+        .await
+        .into_diagnostic()?;
+    probe.connect(server).await.into_diagnostic()?;
+    let ip = probe.local_addr().into_diagnostic()?.ip();
+
+    Ok(SocketAddr::new(ip, bound.port()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+
+    type TestResult = Result<()>;
+
+    /// How the NAT a stand-in server pretends to be behind behaves
+    #[derive(Debug, Clone, Copy)]
+    struct Nat {
+        /// How ports are mapped, or `None` for no NAT at all
+        mapping: Option<Behaviour>,
+        /// Which responses are dropped
+        filtering: Behaviour,
+        /// Whether traffic sent to a mapped port is passed on to its socket
+        hairpinning: bool,
+    }
+
+    impl Nat {
+        /// The public address `peer` is mapped to when it sends to the
+        /// server socket at `to`, whose bit 0 is the port and bit 1 the IP
+        /// address
+        fn mapped(self, peer: SocketAddr, to: usize) -> SocketAddr {
+            let offsets: [u16; 4] = match self.mapping {
+                None => return peer,
+                Some(Behaviour::EndpointIndependent) => [1, 1, 1, 1],
+                Some(Behaviour::AddressDependent) => [1, 1, 2, 2],
+                Some(Behaviour::AddressAndPortDependent) => [1, 2, 3, 4],
+            };
+            SocketAddr::new(peer.ip(), peer.port() ^ offsets[to])
+        }
+    }
+
+    /// Stand in for an RFC 5780 STUN server on 127.0.0.1 and 127.0.0.2,
+    /// reporting the mapped addresses and dropping the responses `nat` would
+    ///
+    /// Returns the server's primary address.
+    async fn stand_in(nat: Nat) -> Result<SocketAddr> {
+        let primary = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .into_diagnostic()?;
+        let alternate_port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .into_diagnostic()?;
+        let (port, other_port) = (
+            primary.local_addr().into_diagnostic()?.port(),
+            alternate_port.local_addr().into_diagnostic()?.port(),
+        );
+        let other_ip = Ipv4Addr::new(127, 0, 0, 2);
+        let sockets = Arc::new([
+            primary,
+            alternate_port,
+            UdpSocket::bind((other_ip, port)).await.into_diagnostic()?,
+            UdpSocket::bind((other_ip, other_port))
+                .await
+                .into_diagnostic()?,
+        ]);
+
+        for index in 0..sockets.len() {
+            tokio::spawn(serve(Arc::clone(&sockets), index, nat));
+        }
+
+        sockets[0].local_addr().into_diagnostic()
+    }
+
+    async fn serve(sockets: Arc<[UdpSocket; 4]>, index: usize, nat: Nat) -> std::io::Result<()> {
+        let other = sockets[3].local_addr()?;
+        let mut buffer = [0; 1500];
+        loop {
+            let (len, peer) = sockets[index].recv_from(&mut buffer).await?;
+            let flags = if len >= 28 { buffer[27] } else { 0 };
+            let (change_ip, change_port) = (flags & 0b100 != 0, flags & 0b10 != 0);
+
+            let dropped = match nat.filtering {
+                Behaviour::EndpointIndependent => false,
+                Behaviour::AddressDependent => change_ip,
+                Behaviour::AddressAndPortDependent => change_ip || change_port,
+            };
+            if dropped {
+                continue;
+            }
+
+            let mapped = nat.mapped(peer, index);
+            if nat.hairpinning && mapped != peer {
+                tokio::spawn(hairpin(mapped, peer));
+            }
+
+            let reply_from = index ^ usize::from(change_port) ^ (usize::from(change_ip) << 1);
+            let response = response(&buffer[8..20], mapped, other);
+            sockets[reply_from].send_to(&response, peer).await?;
+        }
+    }
+
+    /// Pass whatever is sent to the mapped address `public` on to `peer`,
+    /// until another mapping already holds it
+    async fn hairpin(public: SocketAddr, peer: SocketAddr) -> std::io::Result<()> {
+        let socket = UdpSocket::bind(public).await?;
+        let mut buffer = [0; 1500];
+        loop {
+            let (len, _) = socket.recv_from(&mut buffer).await?;
+            socket.send_to(&buffer[..len], peer).await?;
+        }
+    }
+
+    /// Build a Binding Success Response with XOR-MAPPED-ADDRESS and
+    /// OTHER-ADDRESS attributes
+    fn response(transaction_id: &[u8], mapped: SocketAddr, other: SocketAddr) -> Vec<u8> {
+        let (IpAddr::V4(mapped_ip), IpAddr::V4(other_ip)) = (mapped.ip(), other.ip()) else {
+            return Vec::new();
+        };
+
+        let mut message = vec![0x01, 0x01, 0, 24, 0x21, 0x12, 0xA4, 0x42];
+        message.extend_from_slice(transaction_id);
+        message.extend_from_slice(&[0x00, 0x20, 0, 8, 0, 1]);
+        message.extend_from_slice(&(mapped.port() ^ 0x2112).to_be_bytes());
+        message.extend_from_slice(&(u32::from(mapped_ip) ^ 0x2112_A442).to_be_bytes());
+        message.extend_from_slice(&[0x80, 0x2C, 0, 8, 0, 1]);
+        message.extend_from_slice(&other.port().to_be_bytes());
+        message.extend_from_slice(&other_ip.octets());
+        message
+    }
+
+    #[tokio::test]
+    async fn discovers_how_responses_are_filtered() -> TestResult {
+        let filterings = [
+            Behaviour::EndpointIndependent,
+            Behaviour::AddressDependent,
+            Behaviour::AddressAndPortDependent,
+        ];
+        // Filtered probes take a while to give up on, so run the cases at once
+        let results = futures::future::join_all(filterings.map(|filtering| async move {
+            let server = stand_in(Nat {
+                mapping: None,
+                filtering,
+                hairpinning: false,
+            })
+            .await?;
+            discover_at(server, SourceAddresses::default()).await
+        }))
+        .await;
+
+        for (filtering, behaviour) in filterings.into_iter().zip(results) {
+            let behaviour = behaviour?;
+            if behaviour.filtering != filtering || behaviour.is_nat() || !behaviour.hairpinning {
+                return Err(miette!(
+                    "Expected {filtering} filtering without a NAT, got {behaviour:?}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn discovers_how_addresses_are_mapped_behind_a_nat() -> TestResult {
+        let cases = [
+            (Behaviour::EndpointIndependent, true),
+            (Behaviour::AddressDependent, true),
+            (Behaviour::AddressAndPortDependent, true),
+            (Behaviour::EndpointIndependent, false),
+        ];
+        // Hairpinned requests that never arrive take a while to give up on
+        let results = futures::future::join_all(cases.map(|(mapping, hairpinning)| async move {
+            let server = stand_in(Nat {
+                mapping: Some(mapping),
+                filtering: Behaviour::EndpointIndependent,
+                hairpinning,
+            })
+            .await?;
+            discover_at(server, SourceAddresses::default()).await
+        }))
+        .await;
+
+        for ((mapping, hairpinning), behaviour) in cases.into_iter().zip(results) {
+            let behaviour = behaviour?;
+            if behaviour.mapping != mapping
+                || !behaviour.is_nat()
+                || behaviour.hairpinning != hairpinning
+            {
+                return Err(miette!(
+                    "Expected {mapping} mapping behind a NAT, with hairpinning: {hairpinning}, got {behaviour:?}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn classifies_behaviours_with_cone_names() -> TestResult {
+        let local: SocketAddr = "192.168.1.56:40000".parse().into_diagnostic()?;
+        let public: SocketAddr = "203.0.113.7:40000".parse().into_diagnostic()?;
+        let cases = [
+            (
+                local,
+                Behaviour::EndpointIndependent,
+                Behaviour::EndpointIndependent,
+                "open internet",
+            ),
+            (
+                public,
+                Behaviour::EndpointIndependent,
+                Behaviour::EndpointIndependent,
+                "full cone",
+            ),
+            (
+                public,
+                Behaviour::EndpointIndependent,
+                Behaviour::AddressDependent,
+                "restricted cone",
+            ),
+            (
+                public,
+                Behaviour::EndpointIndependent,
+                Behaviour::AddressAndPortDependent,
+                "port-restricted cone",
+            ),
+            (
+                public,
+                Behaviour::AddressDependent,
+                Behaviour::EndpointIndependent,
+                "symmetric",
+            ),
+        ];
+
+        for (mapped, mapping, filtering, expected) in cases {
+            let behaviour = NatBehaviour {
+                local,
+                public: mapped,
+                mapping,
+                filtering,
+                hairpinning: false,
+            };
+
+            if behaviour.classification() != expected {
+                return Err(miette!(
+                    "Expected '{expected}' for {behaviour:?}, got '{}'",
+                    behaviour.classification()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1631 @@
+//! Pluggable sources for discovering external (WAN) IP addresses
+//!
+//! A WAN provider is anything that can tell us the address we appear as on the
+//! public internet. This module defines the `WanProvider` trait that those
+//! sources implement, and `WanProviders`, an ordered registry used to pick
+//! which providers are consulted and in which order.
+//!
+//! ## Built-in providers
+//!
+//! - `google`: TXT lookup of `o-o.myaddr.l.google.com` against Google's nameservers
+//! - `opendns`: A/AAAA lookup of `myip.opendns.com` against OpenDNS's resolvers
+//! - `cloudflare`: CHAOS-class TXT lookup of `whoami.cloudflare` against 1.1.1.1
+//! - `http`: `GET` request to an echo service such as icanhazip or ipify
+//! - `stun`: STUN Binding Request, reporting the UDP-mapped address
+//! - `router`: NAT-PMP, PCP or `UPnP` request to the local gateway, contacting
+//!   nothing outside the local network
+
+use crate::IpVersion::{Ipv4, Ipv6};
+use crate::cgnat::is_shared_address;
+use crate::proxy::Proxy;
+use crate::retry::{IntoTransient, RetryPolicy, TimeLimit, Timeout};
+use crate::{
+    DnsOptions, GOOGLE_NS1, GOOGLE_NS2, GOOGLE_NS3, GOOGLE_NS4, IpVersion, MYADDR_RECORD, MyIps,
+    ResolverCache, SourceAddresses, http, myip, nameserver_resolver, router, stun, user_ips_with,
+};
+use futures::FutureExt;
+use futures::future::{self, BoxFuture};
+use hickory_resolver::proto::op::{Message, Query, ResponseCode};
+use hickory_resolver::proto::rr::{DNSClass, Name, RData, RecordType};
+use miette::{Diagnostic, IntoDiagnostic, Report, Result, bail, miette};
+use std::fmt::Debug;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::net::UdpSocket;
+
+/// How long to wait for a reply to a hand-built DNS query
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A source of external (WAN) IP addresses
+///
+/// Implementations are registered with `WanProviders` and selected by name,
+/// so the name should be short, lowercase and unique.
+pub trait WanProvider: Debug + Send + Sync {
+    /// The name used to select this provider
+    fn name(&self) -> &str;
+
+    /// Discover the external IP addresses of this machine
+    ///
+    /// # Arguments
+    ///
+    /// * `strategy` - The IP version filter to apply (IPv4 or IPv6)
+    ///
+    /// # Returns
+    ///
+    /// A future resolving to the external IP addresses seen by this provider
+    fn find_wan_ip(&self, strategy: IpVersion) -> BoxFuture<'_, Result<MyIps>>;
+
+    /// Forget anything cached by earlier lookups, such as resolvers
+    ///
+    /// This is called after the network changes. Providers that cache
+    /// nothing can rely on the default, which does nothing.
+    fn reset(&self) {}
+}
+
+/// Queries Google's authoritative nameservers for `o-o.myaddr.l.google.com`
+///
+/// See `find_wan_ip` for details of how the lookup is performed. Any
+/// nameserver that answers a TXT query with the address it was sent from can
+/// be used instead, such as your own whoami service, by building the
+/// provider with `Google::new`.
+#[derive(Debug, Clone)]
+pub struct Google {
+    nameservers: Vec<String>,
+    record: String,
+    dns: DnsOptions,
+    ipv4_resolver: ResolverCache,
+    ipv6_resolver: ResolverCache,
+}
+
+impl Google {
+    /// The name this provider is registered under
+    pub const NAME: &'static str = "google";
+    /// Google's nameservers, which answer for `MYADDR_RECORD`
+    pub const NAMESERVERS: [&'static str; 4] = [GOOGLE_NS1, GOOGLE_NS2, GOOGLE_NS3, GOOGLE_NS4];
+
+    /// Ask a specific set of nameservers for a specific TXT record
+    ///
+    /// # Arguments
+    ///
+    /// * `nameservers` - The nameservers to ask, as hostnames or IP addresses,
+    ///   optionally followed by a port
+    /// * `record` - The TXT record that returns the client's IP address
+    #[must_use]
+    pub fn new(nameservers: Vec<String>, record: String) -> Self {
+        Self {
+            nameservers,
+            record,
+            dns: DnsOptions::default(),
+            ipv4_resolver: ResolverCache::default(),
+            ipv6_resolver: ResolverCache::default(),
+        }
+    }
+
+    /// Send the queries, and the nameserver lookups, as described by `dns`
+    #[must_use]
+    pub const fn with_dns_options(mut self, dns: DnsOptions) -> Self {
+        self.dns = dns;
+        self
+    }
+}
+
+impl Default for Google {
+    fn default() -> Self {
+        Self::new(
+            Self::NAMESERVERS.map(String::from).to_vec(),
+            MYADDR_RECORD.to_string(),
+        )
+    }
+}
+
+impl WanProvider for Google {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn find_wan_ip(&self, strategy: IpVersion) -> BoxFuture<'_, Result<MyIps>> {
+        async move {
+            // Try to use cached resolver first
+            let resolver_cell = match strategy {
+                Ipv4 => &self.ipv4_resolver,
+                Ipv6 => &self.ipv6_resolver,
+            };
+
+            let dns_resolver =
+                nameserver_resolver(resolver_cell, &self.nameservers, strategy, self.dns).await?;
+
+            Ok(user_ips_with(&dns_resolver, &self.record).await?.ips)
+        }
+        .boxed()
+    }
+
+    fn reset(&self) {
+        self.ipv4_resolver.reset();
+        self.ipv6_resolver.reset();
+    }
+}
+
+/// Queries OpenDNS's resolvers for `myip.opendns.com`
+///
+/// OpenDNS answers A and AAAA queries for this name with the address the
+/// query came from, so unlike the `google` provider no TXT parsing is needed.
+#[derive(Debug, Clone)]
+pub struct OpenDns {
+    nameservers: Vec<String>,
+    dns: DnsOptions,
+    ipv4_resolver: ResolverCache,
+    ipv6_resolver: ResolverCache,
+}
+
+impl OpenDns {
+    /// The name this provider is registered under
+    pub const NAME: &'static str = "opendns";
+    /// The OpenDNS resolvers that answer for `RECORD`
+    pub const NAMESERVERS: [&'static str; 4] = [
+        "resolver1.opendns.com",
+        "resolver2.opendns.com",
+        "resolver3.opendns.com",
+        "resolver4.opendns.com",
+    ];
+    /// Special OpenDNS record that resolves to the client's IP address
+    pub const RECORD: &'static str = "myip.opendns.com";
+
+    /// Send the queries, and the nameserver lookups, as described by `dns`
+    #[must_use]
+    pub fn with_dns_options(dns: DnsOptions) -> Self {
+        Self {
+            dns,
+            ..Self::default()
+        }
+    }
+
+    /// Ask a specific set of nameservers for `RECORD` instead of OpenDNS's
+    ///
+    /// # Arguments
+    ///
+    /// * `nameservers` - The nameservers to ask, as hostnames or IP addresses,
+    ///   optionally followed by a port
+    #[must_use]
+    pub fn with_nameservers(mut self, nameservers: Vec<String>) -> Self {
+        self.nameservers = nameservers;
+        self
+    }
+}
+
+impl Default for OpenDns {
+    fn default() -> Self {
+        Self {
+            nameservers: Self::NAMESERVERS.map(String::from).to_vec(),
+            dns: DnsOptions::default(),
+            ipv4_resolver: ResolverCache::default(),
+            ipv6_resolver: ResolverCache::default(),
+        }
+    }
+}
+
+impl WanProvider for OpenDns {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn find_wan_ip(&self, strategy: IpVersion) -> BoxFuture<'_, Result<MyIps>> {
+        async move {
+            let resolver_cell = match strategy {
+                Ipv4 => &self.ipv4_resolver,
+                Ipv6 => &self.ipv6_resolver,
+            };
+
+            // The resolver only looks up records for the IP version it talks
+            // over, so this is an A query over IPv4 or an AAAA query over IPv6
+            let dns_resolver =
+                nameserver_resolver(resolver_cell, &self.nameservers, strategy, self.dns).await?;
+            let lookup = dns_resolver
+                .lookup_ip(Self::RECORD)
+                .await
+                .into_transient()?;
+
+            Ok(lookup.iter().map(myip::MyIp::new_plain).collect())
+        }
+        .boxed()
+    }
+
+    fn reset(&self) {
+        self.ipv4_resolver.reset();
+        self.ipv6_resolver.reset();
+    }
+}
+
+/// Queries Cloudflare's resolvers for the CHAOS-class `whoami.cloudflare` record
+///
+/// Cloudflare answers this TXT query in the CH class, rather than the usual IN
+/// class, with the address the query came from. The resolvers are contacted by
+/// IP address, so no nameserver lookup is needed before asking.
+#[derive(Debug, Clone)]
+pub struct Cloudflare {
+    servers: Vec<SocketAddr>,
+    source: SourceAddresses,
+}
+
+impl Cloudflare {
+    /// The name this provider is registered under
+    pub const NAME: &'static str = "cloudflare";
+    /// Cloudflare's public resolvers, which answer for `RECORD`
+    pub const SERVERS: [IpAddr; 4] = [
+        IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
+        IpAddr::V4(Ipv4Addr::new(1, 0, 0, 1)),
+        IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1111)),
+        IpAddr::V6(Ipv6Addr::new(0x2606, 0x4700, 0x4700, 0, 0, 0, 0, 0x1001)),
+    ];
+    /// Special CHAOS-class record that returns the client's IP address
+    pub const RECORD: &'static str = "whoami.cloudflare.";
+
+    /// Ask a specific set of servers instead of Cloudflare's resolvers
+    ///
+    /// # Arguments
+    ///
+    /// * `servers` - The servers to query; only those matching the requested
+    ///   IP version are used for each lookup
+    #[must_use]
+    pub fn with_servers(servers: Vec<SocketAddr>) -> Self {
+        Self {
+            servers,
+            source: SourceAddresses::default(),
+        }
+    }
+
+    /// Send the queries from `source`
+    #[must_use]
+    pub const fn with_source(mut self, source: SourceAddresses) -> Self {
+        self.source = source;
+        self
+    }
+}
+
+impl Default for Cloudflare {
+    fn default() -> Self {
+        Self::with_servers(
+            Self::SERVERS
+                .iter()
+                .map(|ip| SocketAddr::new(*ip, 53))
+                .collect(),
+        )
+    }
+}
+
+impl WanProvider for Cloudflare {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn find_wan_ip(&self, strategy: IpVersion) -> BoxFuture<'_, Result<MyIps>> {
+        async move {
+            let lookups: Vec<_> = self
+                .servers
+                .iter()
+                .filter(|server| match strategy {
+                    Ipv4 => server.is_ipv4(),
+                    Ipv6 => server.is_ipv6(),
+                })
+                .map(|server| chaos_txt_lookup(*server, Self::RECORD, self.source).boxed())
+                .collect();
+
+            if lookups.is_empty() {
+                bail!("No {strategy:?} servers to query");
+            }
+
+            // Use the first server that answers successfully
+            let (answers, _) = future::select_ok(lookups).await?;
+
+            Ok(answers
+                .iter()
+                .filter_map(|answer| IpAddr::from_str(answer).ok())
+                .map(myip::MyIp::new_plain)
+                .collect())
+        }
+        .boxed()
+    }
+}
+
+/// Send a CHAOS-class TXT query directly to a server
+///
+/// The resolver only ever asks questions in the IN class, so this builds the
+/// query by hand and sends it over UDP.
+///
+/// # Arguments
+///
+/// * `server` - The server to send the query to
+/// * `name` - The name to look up
+/// * `source` - The local addresses to send the query from
+///
+/// # Returns
+///
+/// The text of each TXT record in the answer section
+///
+/// # Errors
+///
+/// Returns an error if the query cannot be sent, no reply arrives in time, or
+/// the server responds with an error
+async fn chaos_txt_lookup(
+    server: SocketAddr,
+    name: &str,
+    source: SourceAddresses,
+) -> Result<Vec<String>> {
+    let mut query = Query::query(Name::from_ascii(name).into_diagnostic()?, RecordType::TXT);
+    query.set_query_class(DNSClass::CH);
+
+    let mut request = Message::query();
+    request.metadata.recursion_desired = true;
+    request.add_query(query);
+
+    let socket = UdpSocket::bind(source.local_addr(server.ip())?)
+        .await
+        .into_transient()?;
+    socket.connect(server).await.into_transient()?;
+    socket
+        .send(&request.to_vec().into_diagnostic()?)
+        .await
+        .into_transient()?;
+
+    // Ignore anything that isn't a reply to our query
+    let mut buffer = [0; 4096];
+    let response = loop {
+        let len = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut buffer))
+            .await
+            .map_err(|_| miette!("Timed out waiting for a reply from {server}"))?
+            .into_transient()?;
+
+        if let Ok(response) = Message::from_vec(&buffer[..len])
+            && response.metadata.id == request.metadata.id
+        {
+            break response;
+        }
+    };
+
+    if response.metadata.response_code != ResponseCode::NoError {
+        bail!(
+            "{server} answered {name} with {}",
+            response.metadata.response_code
+        );
+    }
+
+    Ok(response
+        .answers
+        .iter()
+        .filter_map(|record| match &record.data {
+            RData::TXT(txt) => Some(txt.to_string()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Asks an HTTP(S) echo service such as icanhazip or ipify
+///
+/// The service is fetched over the requested IP version, and may reply with
+/// either a bare address or a JSON object containing one. Unless a proxy is
+/// given with `with_proxy`, the proxy environment variables are honoured.
+#[derive(Debug, Clone)]
+pub struct Http {
+    url: http::Url,
+    source: SourceAddresses,
+    proxy: Option<Proxy>,
+}
+
+impl Http {
+    /// The name this provider is registered under
+    pub const NAME: &'static str = "http";
+    /// The echo service used when no other URL is given
+    pub const DEFAULT_URL: &'static str = "https://icanhazip.com/";
+
+    /// Ask a specific echo service
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - The `http://` or `https://` URL of the echo service
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the URL cannot be parsed
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(Self {
+            url: url.parse()?,
+            source: SourceAddresses::default(),
+            proxy: None,
+        })
+    }
+
+    /// Connect to the echo service from `source`
+    #[must_use]
+    pub const fn with_source(mut self, source: SourceAddresses) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Connect to the echo service through `proxy`, rather than any proxy
+    /// in the environment
+    #[must_use]
+    pub fn with_proxy(mut self, proxy: Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            url: http::Url {
+                https: true,
+                host: "icanhazip.com".to_string(),
+                port: 443,
+                path: "/".to_string(),
+            },
+            source: SourceAddresses::default(),
+            proxy: None,
+        }
+    }
+}
+
+impl WanProvider for Http {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn find_wan_ip(&self, strategy: IpVersion) -> BoxFuture<'_, Result<MyIps>> {
+        async move {
+            let proxy = match &self.proxy {
+                Some(proxy) => Some(proxy.clone()),
+                None => Proxy::from_env(&self.url)?,
+            };
+            let body = http::get(&self.url, strategy, self.source, proxy.as_ref()).await?;
+            let ip = http::parse_ip(&body)
+                .ok_or_else(|| miette!("No IP address in the response from {}", self.url))?;
+
+            // A proxy that resolves the service itself may reach it over either IP version
+            if ip.is_ipv4() != matches!(strategy, Ipv4) {
+                bail!("{} answered a {strategy:?} lookup with {ip}", self.url);
+            }
+
+            Ok(vec![myip::MyIp::new_plain(ip)])
+        }
+        .boxed()
+    }
+}
+
+/// Sends a STUN Binding Request and reports the UDP-mapped address
+///
+/// This is the address peer-to-peer UDP traffic appears to come from. The
+/// port is discarded here; use `stun::binding_request` if you need it.
+#[derive(Debug, Clone)]
+pub struct Stun {
+    servers: Vec<String>,
+    source: SourceAddresses,
+}
+
+impl Stun {
+    /// The name this provider is registered under
+    pub const NAME: &'static str = "stun";
+    /// The STUN servers used when no others are given
+    pub const DEFAULT_SERVERS: [&'static str; 2] =
+        ["stun.l.google.com:19302", "stun.cloudflare.com:3478"];
+
+    /// Ask a specific set of STUN servers
+    ///
+    /// # Arguments
+    ///
+    /// * `servers` - The servers to ask, as `host:port`; the port defaults to 3478
+    #[must_use]
+    pub fn with_servers(servers: Vec<String>) -> Self {
+        Self {
+            servers,
+            source: SourceAddresses::default(),
+        }
+    }
+
+    /// Send the Binding Requests from `source`
+    #[must_use]
+    pub const fn with_source(mut self, source: SourceAddresses) -> Self {
+        self.source = source;
+        self
+    }
+}
+
+impl Default for Stun {
+    fn default() -> Self {
+        Self::with_servers(Self::DEFAULT_SERVERS.map(String::from).to_vec())
+    }
+}
+
+impl WanProvider for Stun {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn find_wan_ip(&self, strategy: IpVersion) -> BoxFuture<'_, Result<MyIps>> {
+        async move {
+            if self.servers.is_empty() {
+                bail!("No STUN servers to query");
+            }
+
+            // Use the first server that answers successfully
+            let (mapped, _) = future::select_ok(self.servers.iter().map(|server| {
+                async move {
+                    let addrs = stun::resolve(server, strategy).await?;
+                    if addrs.is_empty() {
+                        bail!("{server} has no {strategy:?} address");
+                    }
+
+                    let (mapped, _) = future::select_ok(
+                        addrs
+                            .into_iter()
+                            .map(|addr| stun::binding_request(addr, self.source).boxed()),
+                    )
+                    .await?;
+                    Ok(mapped)
+                }
+                .boxed()
+            }))
+            .await?;
+
+            Ok(vec![myip::MyIp::new_plain(mapped.ip())])
+        }
+        .boxed()
+    }
+}
+
+/// Asks the local gateway for its external address
+///
+/// NAT-PMP, PCP and `UPnP` IGD are all tried at once, and the first answer is
+/// used. Only IPv4 addresses are reported. A private or shared external
+/// address means there is another NAT beyond the router, so it is rejected
+/// rather than reported as the WAN address.
+#[derive(Debug, Clone, Copy)]
+pub struct Router {
+    gateway: Option<SocketAddr>,
+    search: SocketAddr,
+    source: SourceAddresses,
+}
+
+impl Router {
+    /// The name this provider is registered under
+    pub const NAME: &'static str = "router";
+
+    /// Send NAT-PMP and PCP requests to `gateway`, rather than the gateway
+    /// of the default route
+    #[must_use]
+    pub const fn with_gateway(mut self, gateway: SocketAddr) -> Self {
+        self.gateway = Some(gateway);
+        self
+    }
+
+    /// Send the `UPnP` search to `search`, rather than the SSDP multicast group
+    #[must_use]
+    pub const fn with_search_addr(mut self, search: SocketAddr) -> Self {
+        self.search = search;
+        self
+    }
+
+    /// Send the requests from `source`
+    #[must_use]
+    pub const fn with_source(mut self, source: SourceAddresses) -> Self {
+        self.source = source;
+        self
+    }
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self {
+            gateway: None,
+            search: router::SSDP_ADDR,
+            source: SourceAddresses::default(),
+        }
+    }
+}
+
+impl WanProvider for Router {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn find_wan_ip(&self, strategy: IpVersion) -> BoxFuture<'_, Result<MyIps>> {
+        async move {
+            if matches!(strategy, Ipv6) {
+                bail!("Routers only report IPv4 external addresses");
+            }
+
+            let ip = router::external_ip(self.gateway, self.search, self.source).await?;
+            if ip.is_private() || ip.is_loopback() || ip.is_link_local() || is_shared_address(ip) {
+                bail!("The router's external address {ip} is not public, so there is another NAT beyond it");
+            }
+
+            Ok(vec![myip::MyIp::new_plain(IpAddr::V4(ip))])
+        }
+        .boxed()
+    }
+}
+
+/// Every WAN provider failed to find an address
+///
+/// Each provider's error is kept as a related diagnostic, labelled with the
+/// provider's name, so it can still be inspected with `Report::downcast_ref`.
+#[derive(Debug, Error, Diagnostic)]
+#[error("All WAN providers failed")]
+#[diagnostic(code(whatismyip::all_providers_failed))]
+pub struct AllProvidersFailed {
+    /// Each provider's error, in the order the providers were asked
+    #[related]
+    pub failures: Vec<Report>,
+}
+
+/// An ordered collection of WAN providers
+///
+/// Providers are consulted in the order they were registered. The default
+/// collection contains every built-in provider, in order of preference, and
+/// `WanProviders::DEFAULT` names those worth asking when none are chosen. Each
+/// provider is retried, and the lookup as a whole timed out, according to the
+/// collection's `RetryPolicy`.
+#[derive(Debug, Clone)]
+pub struct WanProviders {
+    providers: Vec<Arc<dyn WanProvider>>,
+    retry: RetryPolicy,
+}
+
+impl Default for WanProviders {
+    fn default() -> Self {
+        Self::builtin()
+    }
+}
+
+impl WanProviders {
+    /// The providers to ask when none are chosen
+    ///
+    /// These only send DNS queries to well-known resolvers. The others contact
+    /// third-party web or STUN services, or search the local network, so they
+    /// are only asked when chosen by name.
+    pub const DEFAULT: [&'static str; 3] = [Google::NAME, OpenDns::NAME, Cloudflare::NAME];
+
+    /// Create a collection with no providers registered
+    #[must_use]
+    pub fn empty() -> Self {
+        Self {
+            providers: Vec::new(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Create a collection containing every built-in provider
+    #[must_use]
+    pub fn builtin() -> Self {
+        Self::with_dns_options(DnsOptions::default())
+    }
+
+    /// Create a collection containing every built-in provider, with DNS
+    /// lookups sent as described by `dns`
+    ///
+    /// The `cloudflare` provider always asks over plain UDP, as its CHAOS-class
+    /// query is built by hand rather than sent through a resolver. Every
+    /// provider, DNS or not, sends its lookups from `dns.source`.
+    #[must_use]
+    pub fn with_dns_options(dns: DnsOptions) -> Self {
+        let mut providers = Self::empty();
+        providers
+            .register(Google::default().with_dns_options(dns))
+            .register(OpenDns::with_dns_options(dns))
+            .register(Cloudflare::default().with_source(dns.source))
+            .register(Http::default().with_source(dns.source))
+            .register(Stun::default().with_source(dns.source))
+            .register(Router::default().with_source(dns.source));
+        providers
+    }
+
+    /// Add a provider to the end of the collection
+    ///
+    /// A provider registered with the same name as an existing one replaces it,
+    /// keeping the original position.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - The provider to add
+    pub fn register(&mut self, provider: impl WanProvider + 'static) -> &mut Self {
+        let provider: Arc<dyn WanProvider> = Arc::new(provider);
+
+        if let Some(existing) = self
+            .providers
+            .iter_mut()
+            .find(|existing| existing.name() == provider.name())
+        {
+            *existing = provider;
+        } else {
+            self.providers.push(provider);
+        }
+
+        self
+    }
+
+    /// Change how providers are retried and timed out
+    ///
+    /// # Arguments
+    ///
+    /// * `retry` - The policy to use for every provider
+    pub const fn set_retry_policy(&mut self, retry: RetryPolicy) -> &mut Self {
+        self.retry = retry;
+        self
+    }
+
+    /// How providers are retried and timed out
+    #[must_use]
+    pub const fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    /// Forget anything the providers have cached from earlier lookups
+    ///
+    /// Call this after the network changes, such as when switching Wi-Fi
+    /// networks or connecting to a VPN, along with `crate::reset`.
+    pub fn reset(&self) {
+        for provider in &self.providers {
+            provider.reset();
+        }
+    }
+
+    /// Look up a registered provider by name
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<Arc<dyn WanProvider>> {
+        self.providers
+            .iter()
+            .find(|provider| provider.name() == name)
+            .cloned()
+    }
+
+    /// The names of the registered providers, in order
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.providers.iter().map(|provider| provider.name())
+    }
+
+    /// The registered providers, in order
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn WanProvider>> {
+        self.providers.iter()
+    }
+
+    /// The number of registered providers
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.providers.len()
+    }
+
+    /// Whether no providers are registered
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.providers.is_empty()
+    }
+
+    /// Build a new collection from a subset of these providers
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - The names of the providers to keep, in the order to use them
+    ///
+    /// # Returns
+    ///
+    /// A collection containing only the named providers, in the order given
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the names is not a registered provider
+    pub fn select<S: AsRef<str>>(&self, names: &[S]) -> Result<Self> {
+        let mut selected = Self::empty();
+        selected.retry = self.retry;
+
+        for name in names {
+            let name = name.as_ref();
+            let Some(provider) = self.get(name) else {
+                bail!(
+                    "Unknown WAN provider '{}', expected one of: {}",
+                    name,
+                    self.names().collect::<Vec<_>>().join(", ")
+                );
+            };
+            selected.providers.push(provider);
+        }
+
+        Ok(selected)
+    }
+
+    /// Discover external IP addresses using the first provider that succeeds
+    ///
+    /// Providers are tried one at a time, in order, so later providers are
+    /// only contacted when the earlier ones fail. Each provider is retried
+    /// before moving on to the next.
+    ///
+    /// # Arguments
+    ///
+    /// * `strategy` - The IP version filter to apply (IPv4 or IPv6)
+    ///
+    /// # Returns
+    ///
+    /// The external IP addresses reported by the first successful provider
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are no providers, or an `AllProvidersFailed`
+    /// if every provider fails. If every provider ran out of time, or the
+    /// overall timeout runs out first, the error is a `retry::Timeout`.
+    pub async fn find_wan_ip(&self, strategy: IpVersion) -> Result<MyIps> {
+        let lookup = async {
+            let mut failures = Vec::with_capacity(self.providers.len());
+
+            for provider in &self.providers {
+                match self.find_wan_ip_with(provider, strategy).await {
+                    Ok(ips) => return Ok(ips),
+                    Err(e) => failures.push(e.wrap_err(format!("{} failed", provider.name()))),
+                }
+            }
+
+            if failures.is_empty() {
+                return Err(miette!("No WAN providers configured"));
+            }
+
+            if failures
+                .iter()
+                .all(|e| e.downcast_ref::<Timeout>().is_some())
+            {
+                return Err(Timeout {
+                    what: "Every WAN provider".to_string(),
+                    after: self.retry.attempt_timeout,
+                    limit: TimeLimit::Attempt,
+                }
+                .into());
+            }
+
+            Err(AllProvidersFailed { failures }.into())
+        };
+
+        self.retry
+            .deadline(&format!("WAN {strategy:?} lookup"), lookup)
+            .await
+    }
+
+    /// Ask a single provider, retrying it according to the policy
+    async fn find_wan_ip_with(
+        &self,
+        provider: &Arc<dyn WanProvider>,
+        strategy: IpVersion,
+    ) -> Result<MyIps> {
+        self.retry
+            .retry(provider.name(), || provider.find_wan_ip(strategy))
+            .await
+    }
+
+    /// Ask every provider at once and find the address a quorum agrees on
+    ///
+    /// Each provider votes for every address it reports. The address with the
+    /// most votes is agreed on if it has at least `quorum` votes and no other
+    /// address has as many.
+    ///
+    /// # Arguments
+    ///
+    /// * `strategy` - The IP version filter to apply (IPv4 or IPv6)
+    /// * `quorum` - How many providers must agree; defaults to a majority of
+    ///   the providers that answered successfully
+    ///
+    /// # Returns
+    ///
+    /// The agreed address, if any, along with every provider's answer.
+    /// Providers still running when the overall timeout runs out are counted
+    /// as failures.
+    pub async fn consensus(&self, strategy: IpVersion, quorum: Option<usize>) -> Consensus {
+        let answers: Vec<ProviderAnswer> =
+            future::join_all(self.providers.iter().map(|provider| async move {
+                ProviderAnswer {
+                    provider: provider.name().to_string(),
+                    result: self
+                        .retry
+                        .deadline(provider.name(), self.find_wan_ip_with(provider, strategy))
+                        .await,
+                }
+            }))
+            .await;
+
+        let mut votes: Vec<(IpAddr, usize)> = Vec::new();
+        for ips in answers
+            .iter()
+            .filter_map(|answer| answer.result.as_ref().ok())
+        {
+            let mut seen = Vec::with_capacity(ips.len());
+            for ip in ips.iter().map(myip::MyIp::ip) {
+                // A provider only gets one vote per address
+                if seen.contains(&ip) {
+                    continue;
+                }
+                seen.push(ip);
+
+                match votes.iter_mut().find(|(candidate, _)| *candidate == ip) {
+                    Some((_, count)) => *count += 1,
+                    None => votes.push((ip, 1)),
+                }
+            }
+        }
+
+        let successes = answers
+            .iter()
+            .filter(|answer| answer.result.is_ok())
+            .count();
+        let quorum = quorum.unwrap_or(successes / 2 + 1).max(1);
+        let most = votes.iter().map(|(_, count)| *count).max().unwrap_or(0);
+        let mut leaders = votes.iter().filter(|(_, count)| *count == most);
+
+        let agreed = match (leaders.next(), leaders.next()) {
+            (Some((ip, count)), None) if *count >= quorum => Some(*ip),
+            _ => None,
+        };
+
+        Consensus {
+            strategy,
+            quorum,
+            agreed,
+            answers,
+        }
+    }
+}
+
+/// What a single provider answered during a consensus lookup
+#[derive(Debug)]
+pub struct ProviderAnswer {
+    /// The name of the provider
+    pub provider: String,
+    /// The addresses it reported, or why it failed
+    pub result: Result<MyIps>,
+}
+
+/// The outcome of asking several providers for the same address
+#[derive(Debug)]
+pub struct Consensus {
+    /// The IP version that was asked for
+    pub strategy: IpVersion,
+    /// How many providers had to agree
+    pub quorum: usize,
+    /// The address a quorum of providers agreed on, if any
+    pub agreed: Option<IpAddr>,
+    /// Every provider's answer, in the order the providers are registered
+    pub answers: Vec<ProviderAnswer>,
+}
+
+impl Consensus {
+    /// Providers that answered, but not with the agreed address
+    ///
+    /// When there is no agreed address, every provider that answered is
+    /// included.
+    pub fn dissenters(&self) -> impl Iterator<Item = (&str, &MyIps)> {
+        self.answers.iter().filter_map(|answer| {
+            let ips = answer.result.as_ref().ok()?;
+            let agrees = self
+                .agreed
+                .is_some_and(|agreed| ips.iter().any(|ip| ip.ip() == agreed));
+
+            (!agrees).then_some((answer.provider.as_str(), ips))
+        })
+    }
+
+    /// Providers that failed to answer
+    pub fn failures(&self) -> impl Iterator<Item = (&str, &miette::Report)> {
+        self.answers.iter().filter_map(|answer| {
+            answer
+                .result
+                .as_ref()
+                .err()
+                .map(|e| (answer.provider.as_str(), e))
+        })
+    }
+
+    /// The agreed address as a lookup result
+    ///
+    /// # Errors
+    ///
+    /// Returns an error describing what each provider said when no quorum
+    /// was reached
+    pub fn into_result(self) -> Result<MyIps> {
+        if let Some(agreed) = self.agreed {
+            return Ok(vec![myip::MyIp::new_plain(agreed)]);
+        }
+
+        let answers: Vec<String> = self
+            .dissenters()
+            .map(|(provider, ips)| format!("{provider}: {}", format_answer(ips)))
+            .chain(
+                self.failures()
+                    .map(|(provider, e)| format!("{provider}: failed ({e})")),
+            )
+            .collect();
+
+        Err(miette!(
+            "No WAN {:?} address was agreed on by at least {} providers: {}",
+            self.strategy,
+            self.quorum,
+            answers.join("; ")
+        ))
+    }
+}
+
+/// Format the addresses reported by a provider as a comma separated list
+#[must_use]
+pub fn format_answer(ips: &MyIps) -> String {
+    if ips.is_empty() {
+        return "no address".to_string();
+    }
+
+    ips.iter()
+        .map(|ip| ip.ip().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::myip::MyIp;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    type TestResult = Result<()>;
+
+    #[derive(Debug)]
+    struct Fixed(&'static str, Option<IpAddr>);
+
+    impl WanProvider for Fixed {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn find_wan_ip(&self, _strategy: IpVersion) -> BoxFuture<'_, Result<MyIps>> {
+            let result = self
+                .1
+                .map(|ip| vec![MyIp::new_plain(ip)])
+                .ok_or_else(|| miette!("{} is down", self.0));
+            async move { result }.boxed()
+        }
+    }
+
+    /// A provider that never answers
+    #[derive(Debug)]
+    struct Hanging(&'static str);
+
+    impl WanProvider for Hanging {
+        fn name(&self) -> &str {
+            self.0
+        }
+
+        fn find_wan_ip(&self, _strategy: IpVersion) -> BoxFuture<'_, Result<MyIps>> {
+            future::pending().boxed()
+        }
+    }
+
+    /// Retries quickly, so that hanging providers time out within the test
+    const FAST: RetryPolicy = RetryPolicy {
+        attempts: 2,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(1),
+        attempt_timeout: Duration::from_millis(20),
+        timeout: None,
+    };
+
+    const FIRST: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const SECOND: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2));
+
+    #[test]
+    fn builtin_providers_are_registered() -> TestResult {
+        let names: Vec<_> = WanProviders::default().names().map(String::from).collect();
+
+        let expected = vec![
+            Google::NAME,
+            OpenDns::NAME,
+            Cloudflare::NAME,
+            Http::NAME,
+            Stun::NAME,
+            Router::NAME,
+        ];
+        if names != expected {
+            return Err(miette!("Expected {expected:?}, got {names:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn register_replaces_providers_with_the_same_name() -> TestResult {
+        let mut providers = WanProviders::empty();
+        providers
+            .register(Fixed("a", Some(FIRST)))
+            .register(Fixed("b", None))
+            .register(Fixed("a", Some(SECOND)));
+
+        let names: Vec<_> = providers.names().collect();
+        if names != vec!["a", "b"] {
+            return Err(miette!("Expected [a, b], got {names:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn select_orders_providers_by_the_names_given() -> TestResult {
+        let mut providers = WanProviders::empty();
+        providers
+            .register(Fixed("a", None))
+            .register(Fixed("b", None))
+            .register(Fixed("c", None));
+
+        let selected = providers.select(&["c", "a"])?;
+        let names: Vec<_> = selected.names().collect();
+
+        if names != vec!["c", "a"] {
+            return Err(miette!("Expected [c, a], got {names:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn select_rejects_unknown_providers() -> TestResult {
+        if WanProviders::default().select(&["nonsense"]).is_ok() {
+            return Err(miette!("Expected an unknown provider to be rejected"));
+        }
+
+        Ok(())
+    }
+
+    /// Answer each UDP datagram sent to a local socket with whatever
+    /// `respond` makes of it, leaving it unanswered when that's `None`
+    async fn udp_stand_in(
+        respond: impl Fn(&[u8], SocketAddr) -> Option<Vec<u8>> + Send + 'static,
+    ) -> Result<SocketAddr> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .into_diagnostic()?;
+        let addr = socket.local_addr().into_diagnostic()?;
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 1100];
+            while let Ok((len, peer)) = socket.recv_from(&mut buffer).await {
+                if let Some(response) = respond(&buffer[..len], peer)
+                    && socket.send_to(&response, peer).await.is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        Ok(addr)
+    }
+
+    async fn tcp_txt_stand_in(answer: &'static str) -> Result<SocketAddr> {
+        let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .into_diagnostic()?;
+        let addr = listener.local_addr().into_diagnostic()?;
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve_tcp_txt(stream, answer));
+            }
+        });
+
+        Ok(addr)
+    }
+
+    async fn serve_tcp_txt(
+        mut stream: tokio::net::TcpStream,
+        answer: &'static str,
+    ) -> std::io::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Each message over TCP is preceded by its length
+        loop {
+            let mut request = vec![0; usize::from(stream.read_u16().await?)];
+            stream.read_exact(&mut request).await?;
+
+            let bytes = txt_response(&request, DNSClass::IN, answer)?;
+            let len = u16::try_from(bytes.len()).map_err(std::io::Error::other)?;
+            stream.write_u16(len).await?;
+            stream.write_all(&bytes).await?;
+        }
+    }
+
+    /// Answer a NAT-PMP external address request with `external`, leaving
+    /// PCP requests and SSDP searches unanswered
+    fn nat_pmp_response(request: &[u8], external: Ipv4Addr) -> Option<Vec<u8>> {
+        if request != [0, 0] {
+            return None;
+        }
+        let mut response = vec![0, 128, 0, 0, 0, 0, 0, 1];
+        response.extend_from_slice(&external.octets());
+        Some(response)
+    }
+
+    /// Answer an A or AAAA query for `OpenDns::RECORD` with `answer`,
+    /// refusing anything else
+    fn address_response(request: &[u8], answer: IpAddr) -> std::io::Result<Vec<u8>> {
+        use hickory_resolver::proto::rr::Record;
+        use hickory_resolver::proto::rr::rdata::{A, AAAA};
+
+        let request = Message::from_vec(request).map_err(std::io::Error::other)?;
+
+        let mut response = Message::response(request.metadata.id, request.metadata.op_code);
+        let query = request.queries[0].clone();
+        let rdata = match (query.query_type(), answer) {
+            (RecordType::A, IpAddr::V4(ip)) => Some(RData::A(A(ip))),
+            (RecordType::AAAA, IpAddr::V6(ip)) => Some(RData::AAAA(AAAA(ip))),
+            _ => None,
+        };
+        match rdata {
+            Some(rdata) if query.name().to_ascii() == "myip.opendns.com." => {
+                response.add_answer(Record::from_rdata(query.name().clone(), 0, rdata));
+            }
+            _ => response.metadata.response_code = ResponseCode::Refused,
+        }
+        response.add_query(query);
+
+        response.to_vec().map_err(std::io::Error::other)
+    }
+
+    /// Answer a TXT query in `class` with `answer`, refusing anything asked
+    /// in another class
+    fn txt_response(request: &[u8], class: DNSClass, answer: &str) -> std::io::Result<Vec<u8>> {
+        use hickory_resolver::proto::rr::Record;
+        use hickory_resolver::proto::rr::rdata::TXT;
+
+        let request = Message::from_vec(request).map_err(std::io::Error::other)?;
+
+        let mut response = Message::response(request.metadata.id, request.metadata.op_code);
+        let query = request.queries[0].clone();
+        if query.query_class() == class && query.query_type() == RecordType::TXT {
+            let mut record = Record::from_rdata(
+                query.name().clone(),
+                0,
+                RData::TXT(TXT::new(vec![answer.to_string()])),
+            );
+            record.dns_class = class;
+            response.add_answer(record);
+        } else {
+            response.metadata.response_code = ResponseCode::Refused;
+        }
+        response.add_query(query);
+
+        response.to_vec().map_err(std::io::Error::other)
+    }
+
+    #[tokio::test]
+    async fn google_asks_the_configured_nameserver_and_record() -> TestResult {
+        let server =
+            udp_stand_in(|request, _| txt_response(request, DNSClass::IN, "192.0.2.1").ok())
+                .await?;
+        let ips = Google::new(vec![server.to_string()], "whoami.example.".to_string())
+            .find_wan_ip(IpVersion::Ipv4)
+            .await?;
+
+        if ips != vec![MyIp::new_plain(FIRST)] {
+            return Err(miette!("Expected [{FIRST}], got {ips:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reset_forgets_cached_resolvers() -> TestResult {
+        let server =
+            udp_stand_in(|request, _| txt_response(request, DNSClass::IN, "192.0.2.1").ok())
+                .await?;
+        let google = Google::new(vec![server.to_string()], "whoami.example.".to_string());
+        google.find_wan_ip(IpVersion::Ipv4).await?;
+        if google.ipv4_resolver.get().is_none() {
+            return Err(miette!("Expected the resolver to be cached"));
+        }
+
+        google.reset();
+        if google.ipv4_resolver.get().is_some() {
+            return Err(miette!("Expected the resolver to be forgotten"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn google_skips_nameservers_that_fail() -> TestResult {
+        // The IPv6 nameserver fails straight away when asked over IPv4
+        let server =
+            udp_stand_in(|request, _| txt_response(request, DNSClass::IN, "192.0.2.1").ok())
+                .await?;
+        let ips = Google::new(
+            vec!["2001:db8::53".to_string(), server.to_string()],
+            "whoami.example.".to_string(),
+        )
+        .find_wan_ip(IpVersion::Ipv4)
+        .await?;
+
+        if ips != vec![MyIp::new_plain(FIRST)] {
+            return Err(miette!("Expected [{FIRST}], got {ips:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn google_sends_queries_over_the_configured_transport() -> TestResult {
+        // The stand-in only listens on TCP, so a UDP query would go unanswered
+        let server = tcp_txt_stand_in("192.0.2.1").await?;
+        let ips = Google::new(vec![server.to_string()], "whoami.example.".to_string())
+            .with_dns_options(DnsOptions {
+                transport: crate::Transport::Tcp,
+                ..DnsOptions::default()
+            })
+            .find_wan_ip(IpVersion::Ipv4)
+            .await?;
+
+        if ips != vec![MyIp::new_plain(FIRST)] {
+            return Err(miette!("Expected [{FIRST}], got {ips:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn google_sends_queries_from_the_source_address() -> TestResult {
+        // Any address in 127.0.0.0/8 reaches the loopback interface
+        let source = Ipv4Addr::new(127, 0, 0, 2);
+        let server = udp_stand_in(|request, peer| {
+            txt_response(request, DNSClass::IN, &peer.ip().to_string()).ok()
+        })
+        .await?;
+        let ips = Google::new(vec![server.to_string()], "whoami.example.".to_string())
+            .with_dns_options(DnsOptions {
+                source: SourceAddresses::from_ips([IpAddr::V4(source)])?,
+                ..DnsOptions::default()
+            })
+            .find_wan_ip(IpVersion::Ipv4)
+            .await?;
+
+        if ips != vec![MyIp::new_plain(IpAddr::V4(source))] {
+            return Err(miette!("Expected [{source}], got {ips:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn opendns_reads_the_address_answer() -> TestResult {
+        let server = udp_stand_in(|request, _| address_response(request, FIRST).ok()).await?;
+        let ips = OpenDns::default()
+            .with_nameservers(vec![server.to_string()])
+            .find_wan_ip(IpVersion::Ipv4)
+            .await?;
+
+        if ips != vec![MyIp::new_plain(FIRST)] {
+            return Err(miette!("Expected [{FIRST}], got {ips:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cloudflare_reads_the_chaos_txt_answer() -> TestResult {
+        let server =
+            udp_stand_in(|request, _| txt_response(request, DNSClass::CH, "192.0.2.1").ok())
+                .await?;
+        let ips = Cloudflare::with_servers(vec![server])
+            .find_wan_ip(IpVersion::Ipv4)
+            .await?;
+
+        if ips != vec![MyIp::new_plain(FIRST)] {
+            return Err(miette!("Expected [{FIRST}], got {ips:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cloudflare_needs_a_server_for_the_ip_version() -> TestResult {
+        let server = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 53);
+        let result = Cloudflare::with_servers(vec![server])
+            .find_wan_ip(IpVersion::Ipv6)
+            .await;
+
+        if result.is_ok() {
+            return Err(miette!("Expected an error with no IPv6 servers"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_wan_ip_falls_back_to_later_providers() -> TestResult {
+        let mut providers = WanProviders::empty();
+        providers
+            .register(Fixed("down", None))
+            .register(Fixed("first", Some(FIRST)))
+            .register(Fixed("second", Some(SECOND)));
+
+        let ips = providers.find_wan_ip(IpVersion::Ipv4).await?;
+
+        if ips != vec![MyIp::new_plain(FIRST)] {
+            return Err(miette!("Expected [{FIRST}], got {ips:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn consensus_agrees_on_the_majority_answer() -> TestResult {
+        let mut providers = WanProviders::empty();
+        providers
+            .register(Fixed("a", Some(FIRST)))
+            .register(Fixed("b", Some(SECOND)))
+            .register(Fixed("c", Some(FIRST)))
+            .register(Fixed("d", None));
+
+        let consensus = providers.consensus(IpVersion::Ipv4, None).await;
+
+        if consensus.agreed != Some(FIRST) {
+            return Err(miette!("Expected {FIRST} to be agreed, got {consensus:?}"));
+        }
+
+        let dissenters: Vec<_> = consensus.dissenters().map(|(name, _)| name).collect();
+        if dissenters != vec!["b"] {
+            return Err(miette!("Expected only b to dissent, got {dissenters:?}"));
+        }
+
+        let failures: Vec<_> = consensus.failures().map(|(name, _)| name).collect();
+        if failures != vec!["d"] {
+            return Err(miette!("Expected only d to fail, got {failures:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn consensus_fails_without_a_quorum() -> TestResult {
+        let mut providers = WanProviders::empty();
+        providers
+            .register(Fixed("a", Some(FIRST)))
+            .register(Fixed("b", Some(SECOND)));
+
+        let consensus = providers.consensus(IpVersion::Ipv4, None).await;
+        if let Some(agreed) = consensus.agreed {
+            return Err(miette!("Expected a split vote, but {agreed} was agreed"));
+        }
+
+        let Err(e) = consensus.into_result() else {
+            return Err(miette!("Expected a split vote to be an error"));
+        };
+        let message = e.to_string();
+        if !message.contains("a: 192.0.2.1") || !message.contains("b: 192.0.2.2") {
+            return Err(miette!("Expected both answers in '{message}'"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn consensus_respects_an_explicit_quorum() -> TestResult {
+        let mut providers = WanProviders::empty();
+        providers
+            .register(Fixed("a", Some(FIRST)))
+            .register(Fixed("b", Some(FIRST)))
+            .register(Fixed("c", Some(SECOND)));
+
+        let consensus = providers.consensus(IpVersion::Ipv4, Some(3)).await;
+        if let Some(agreed) = consensus.agreed {
+            return Err(miette!(
+                "Expected no agreement with a quorum of 3, got {agreed}"
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_wan_ip_reports_every_failure() -> TestResult {
+        let mut providers = WanProviders::empty();
+        providers
+            .register(Fixed("one", None))
+            .register(Fixed("two", None));
+
+        let Err(e) = providers.find_wan_ip(IpVersion::Ipv4).await else {
+            return Err(miette!("Expected every provider to fail"));
+        };
+        let Some(all) = e.downcast_ref::<AllProvidersFailed>() else {
+            return Err(miette!("Expected AllProvidersFailed, got {e:?}"));
+        };
+
+        let messages: Vec<String> = all
+            .failures
+            .iter()
+            .map(|failure| failure.root_cause().to_string())
+            .collect();
+        if messages != ["one is down", "two is down"] {
+            return Err(miette!("Expected both failures, got {messages:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_wan_ip_keeps_each_timeout_diagnosable() -> TestResult {
+        let mut providers = WanProviders::empty();
+        providers
+            .register(Hanging("slow"))
+            .register(Fixed("down", None))
+            .set_retry_policy(FAST);
+
+        let Err(e) = providers.find_wan_ip(IpVersion::Ipv4).await else {
+            return Err(miette!("Expected every provider to fail"));
+        };
+        let timed_out = e
+            .downcast_ref::<AllProvidersFailed>()
+            .and_then(|all| all.failures.first())
+            .and_then(|failure| failure.downcast_ref::<Timeout>());
+
+        if timed_out.map(|timeout| timeout.limit) != Some(TimeLimit::Attempt) {
+            return Err(miette!(
+                "Expected the first failure to be a timeout, got {e:?}"
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn find_wan_ip_times_out_when_every_provider_does() -> TestResult {
+        let mut providers = WanProviders::empty();
+        providers
+            .register(Hanging("one"))
+            .register(Hanging("two"))
+            .set_retry_policy(FAST);
+
+        let Err(e) = providers.find_wan_ip(IpVersion::Ipv4).await else {
+            return Err(miette!("Expected every provider to fail"));
+        };
+
+        let limit = e.downcast_ref::<Timeout>().map(|timeout| timeout.limit);
+        if limit != Some(TimeLimit::Attempt) {
+            return Err(miette!("Expected a timeout, got {e:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn router_asks_the_gateway() -> TestResult {
+        let external = Ipv4Addr::new(203, 0, 113, 7);
+        let gateway = udp_stand_in(move |request, _| nat_pmp_response(request, external)).await?;
+        let ips = Router::default()
+            .with_gateway(gateway)
+            .with_search_addr(gateway)
+            .find_wan_ip(IpVersion::Ipv4)
+            .await?;
+
+        if ips != vec![MyIp::new_plain(IpAddr::V4(external))] {
+            return Err(miette!("Expected [{external}], got {ips:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn router_rejects_addresses_behind_another_nat() -> TestResult {
+        for external in [Ipv4Addr::new(100, 64, 1, 2), Ipv4Addr::new(192, 168, 0, 2)] {
+            let gateway =
+                udp_stand_in(move |request, _| nat_pmp_response(request, external)).await?;
+            let result = Router::default()
+                .with_gateway(gateway)
+                .with_search_addr(gateway)
+                .find_wan_ip(IpVersion::Ipv4)
+                .await;
+
+            if let Ok(ips) = result {
+                return Err(miette!("Expected {external} to be rejected, got {ips:?}"));
+            }
+        }
+
+        Ok(())
+    }
+}
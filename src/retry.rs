@@ -0,0 +1,437 @@
+//! Retrying and timing out WAN lookups
+//!
+//! Every lookup attempt is given its own timeout, and attempts that fail for a
+//! transient reason, such as a timeout or an unreachable network, are retried
+//! after an exponentially growing, jittered delay. Other failures, such as a
+//! missing record or a refused request, would only fail again, so they are
+//! returned straight away. An overall timeout
+//! on top of this, 10 seconds by default, makes a dead network fail quickly
+//! instead of waiting for each attempt in turn.
+
+use hickory_resolver::net::NetError;
+use miette::{Diagnostic, IntoDiagnostic, Report, Result};
+use std::future::Future;
+use std::hash::{BuildHasher, RandomState};
+use std::io::ErrorKind;
+use std::time::Duration;
+use thiserror::Error;
+
+/// A lookup that did not finish in time
+///
+/// This is returned, wrapped in a `miette::Report`, whenever an attempt or a
+/// whole lookup runs out of time, so it can be told apart from other failures
+/// with `Report::downcast_ref`.
+#[derive(Debug, Clone, Error, Diagnostic, PartialEq, Eq)]
+#[error("{what} timed out after {after:?}")]
+#[diagnostic(
+    code(whatismyip::timeout),
+    help(
+        "The network may be down, or blocking the lookup; try a longer {}",
+        limit.flag()
+    )
+)]
+pub struct Timeout {
+    /// What was being waited for
+    pub what: String,
+    /// How long was waited
+    pub after: Duration,
+    /// Which of the policy's time limits ran out
+    pub limit: TimeLimit,
+}
+
+/// The time limits a `RetryPolicy` puts on a lookup
+#[derive(Copy, Debug, Clone, PartialEq, Eq)]
+pub enum TimeLimit {
+    /// A single attempt ran longer than `RetryPolicy::attempt_timeout`
+    Attempt,
+    /// The whole lookup ran longer than `RetryPolicy::timeout`
+    Lookup,
+}
+
+impl TimeLimit {
+    /// The command line option that sets the limit
+    #[must_use]
+    pub const fn flag(self) -> &'static str {
+        match self {
+            Self::Attempt => "--attempt-timeout",
+            Self::Lookup => "--timeout",
+        }
+    }
+}
+
+/// A failure that may clear up by the next attempt, such as a lost packet or
+/// an unreachable network
+///
+/// Only timeouts and failures marked as transient are retried. Errors from
+/// sockets and DNS queries can be marked with `IntoTransient::into_transient`.
+#[derive(Debug, Error, Diagnostic)]
+#[error(transparent)]
+pub struct Transient(Box<dyn std::error::Error + Send + Sync>);
+
+impl Transient {
+    /// Mark an error, or an error message, as transient
+    pub fn new(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self(error.into())
+    }
+}
+
+/// Convert errors into diagnostics, marking those worth retrying as `Transient`
+pub trait IntoTransient<T> {
+    /// Convert the error into a `Report`, as `IntoDiagnostic` does, marking it
+    /// as `Transient` if it's a network failure or a DNS query that went
+    /// unanswered. I/O errors that say the request itself was wrong, such as
+    /// invalid data or a source address that isn't on this machine, are not
+    /// transient.
+    ///
+    /// # Errors
+    ///
+    /// Returns the converted error, if there was one
+    fn into_transient(self) -> Result<T>;
+}
+
+impl<T, E: std::error::Error + Send + Sync + 'static> IntoTransient<T> for Result<T, E> {
+    fn into_transient(self) -> Result<T> {
+        self.or_else(|error| {
+            if is_transient_cause(&error) {
+                Err(Transient::new(error).into())
+            } else {
+                Err(error).into_diagnostic()
+            }
+        })
+    }
+}
+
+/// Whether an error, or anything that caused it, is a transient network failure
+fn is_transient_cause(error: &(dyn std::error::Error + 'static)) -> bool {
+    let transient = error.downcast_ref::<std::io::Error>().is_some_and(|error| {
+        !matches!(
+            error.kind(),
+            ErrorKind::InvalidData
+                | ErrorKind::InvalidInput
+                | ErrorKind::PermissionDenied
+                | ErrorKind::AddrNotAvailable
+                | ErrorKind::Unsupported
+        )
+    }) || error.downcast_ref::<NetError>().is_some_and(|error| {
+        matches!(
+            error,
+            NetError::Busy | NetError::NoConnections | NetError::Io(_) | NetError::Timeout
+        )
+    });
+
+    transient || error.source().is_some_and(is_transient_cause)
+}
+
+/// Whether a failed attempt is worth trying again
+///
+/// # Returns
+///
+/// Whether the error is a `Timeout` or was marked as `Transient`
+#[must_use]
+pub fn is_transient(error: &Report) -> bool {
+    error.downcast_ref::<Timeout>().is_some() || error.downcast_ref::<Transient>().is_some()
+}
+
+/// How often, and for how long, to try a lookup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times to try, including the first attempt
+    pub attempts: u32,
+    /// How long to wait before the first retry
+    pub initial_backoff: Duration,
+    /// The longest to ever wait between attempts
+    pub max_backoff: Duration,
+    /// How long each attempt may take
+    pub attempt_timeout: Duration,
+    /// How long the whole lookup may take, across every attempt, or `None` to
+    /// wait for every attempt to finish
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(2),
+            attempt_timeout: Duration::from_secs(5),
+            timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait before a retry
+    ///
+    /// The delay doubles with each retry, up to `max_backoff`, and a random
+    /// amount of up to half of it is taken off so that clients started
+    /// together don't retry in lockstep.
+    ///
+    /// # Arguments
+    ///
+    /// * `retry` - Which retry this is, starting at 0 for the first
+    #[must_use]
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let delay = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(self.max_backoff);
+
+        // RandomState is seeded from the operating system's random source
+        let jitter = RandomState::new().hash_one(retry) % 1_000;
+        delay / 2 + delay / 2 * u32::try_from(jitter).unwrap_or_default() / 1_000
+    }
+
+    /// Try a lookup until it succeeds, fails for good, or the attempts run out
+    ///
+    /// Only failures that `is_transient` says may clear up are retried.
+    ///
+    /// # Arguments
+    ///
+    /// * `what` - What is being looked up, used in timeout errors
+    /// * `attempt` - Starts a new attempt at the lookup
+    ///
+    /// # Returns
+    ///
+    /// The result of the first successful attempt
+    ///
+    /// # Errors
+    ///
+    /// Returns the error from the last attempt if every attempt fails, which
+    /// is a `Timeout` if that attempt ran out of time, or the first error that
+    /// isn't transient
+    pub async fn retry<T, F, Fut>(&self, what: &str, mut attempt: F) -> Result<T>
+    where
+        F: FnMut() -> Fut + Send,
+        Fut: Future<Output = Result<T>> + Send,
+    {
+        let mut retry = 0;
+        loop {
+            let result = within(what, self.attempt_timeout, TimeLimit::Attempt, attempt()).await;
+
+            match result {
+                Ok(value) => return Ok(value),
+                Err(error) if retry + 1 >= self.attempts || !is_transient(&error) => {
+                    return Err(error);
+                }
+                Err(_) => {
+                    tokio::time::sleep(self.backoff(retry)).await;
+                    retry += 1;
+                }
+            }
+        }
+    }
+
+    /// Limit a lookup to the overall timeout, if there is one
+    ///
+    /// # Arguments
+    ///
+    /// * `what` - What is being looked up, used in timeout errors
+    /// * `lookup` - The lookup to limit
+    ///
+    /// # Errors
+    ///
+    /// Returns a `Timeout` if the lookup takes too long, or the lookup's own
+    /// error if it fails
+    pub async fn deadline<T>(
+        &self,
+        what: &str,
+        lookup: impl Future<Output = Result<T>> + Send,
+    ) -> Result<T> {
+        match self.timeout {
+            Some(timeout) => within(what, timeout, TimeLimit::Lookup, lookup).await,
+            None => lookup.await,
+        }
+    }
+}
+
+/// Run a future, giving up with a `Timeout` if it takes longer than `after`
+async fn within<T>(
+    what: &str,
+    after: Duration,
+    limit: TimeLimit,
+    future: impl Future<Output = Result<T>> + Send,
+) -> Result<T> {
+    tokio::time::timeout(after, future)
+        .await
+        .unwrap_or_else(|_| {
+            Err(Timeout {
+                what: what.to_string(),
+                after,
+                limit,
+            }
+            .into())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miette::miette;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    type TestResult = Result<()>;
+
+    const FAST: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        initial_backoff: Duration::from_millis(1),
+        max_backoff: Duration::from_millis(4),
+        attempt_timeout: Duration::from_millis(50),
+        timeout: None,
+    };
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_maximum() -> TestResult {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+            ..RetryPolicy::default()
+        };
+
+        for (retry, full) in [(0, 100), (1, 200), (2, 350), (10, 350), (40, 350)] {
+            let full = Duration::from_millis(full);
+            let backoff = policy.backoff(retry);
+            if backoff < full / 2 || backoff > full {
+                return Err(miette!(
+                    "Expected retry {} to wait between {:?} and {:?}, got {:?}",
+                    retry,
+                    full / 2,
+                    full,
+                    backoff
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_stops_at_the_first_success() -> TestResult {
+        let calls = AtomicU32::new(0);
+        let value = FAST
+            .retry("lookup", || async {
+                match calls.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(Transient::new("first attempt fails").into()),
+                    _ => Ok(42),
+                }
+            })
+            .await?;
+
+        if value != 42 || calls.load(Ordering::SeqCst) != 2 {
+            return Err(miette!(
+                "Expected 42 after 2 attempts, got {} after {}",
+                value,
+                calls.load(Ordering::SeqCst)
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_after_the_last_attempt() -> TestResult {
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = FAST
+            .retry("lookup", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(Transient::new("always fails").into())
+            })
+            .await;
+
+        if result.is_ok() || calls.load(Ordering::SeqCst) != FAST.attempts {
+            return Err(miette!(
+                "Expected {} failed attempts, got {}",
+                FAST.attempts,
+                calls.load(Ordering::SeqCst)
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_gives_up_on_permanent_failures() -> TestResult {
+        let calls = AtomicU32::new(0);
+        let result: Result<()> = FAST
+            .retry("lookup", || async {
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err(miette!("no such record"))
+            })
+            .await;
+
+        if result.is_ok() || calls.load(Ordering::SeqCst) != 1 {
+            return Err(miette!(
+                "Expected 1 failed attempt, got {}",
+                calls.load(Ordering::SeqCst)
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_network_failures_are_transient() -> TestResult {
+        use std::io::Error;
+
+        let refused: Result<()> = Err(Error::from(ErrorKind::ConnectionRefused)).into_transient();
+        let timed_out: Result<()> = Err(NetError::Timeout).into_transient();
+        let missing: Result<()> = Err(NetError::Message("no records")).into_transient();
+        let invalid: Result<()> = "x".parse::<u8>().map(drop).into_transient();
+        let bad_certificate: Result<()> = Err(Error::from(ErrorKind::InvalidData)).into_transient();
+
+        for (result, expected) in [
+            (refused, true),
+            (timed_out, true),
+            (missing, false),
+            (invalid, false),
+            (bad_certificate, false),
+        ] {
+            let error = result.err().ok_or_else(|| miette!("Expected an error"))?;
+            if is_transient(&error) != expected {
+                return Err(miette!("Expected '{error}' to be transient: {expected}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn retry_times_out_slow_attempts() -> TestResult {
+        let error = FAST
+            .retry("lookup", std::future::pending::<Result<()>>)
+            .await
+            .err()
+            .ok_or_else(|| miette!("Expected a hanging lookup to fail"))?;
+
+        let expected = Timeout {
+            what: "lookup".to_string(),
+            after: FAST.attempt_timeout,
+            limit: TimeLimit::Attempt,
+        };
+        if error.downcast_ref::<Timeout>() != Some(&expected) {
+            return Err(miette!("Expected {expected:?}, got {error:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn deadline_limits_the_whole_lookup() -> TestResult {
+        let policy = RetryPolicy {
+            timeout: Some(Duration::from_millis(10)),
+            ..FAST
+        };
+        let error = policy
+            .deadline("everything", std::future::pending::<Result<()>>())
+            .await
+            .err()
+            .ok_or_else(|| miette!("Expected a hanging lookup to fail"))?;
+
+        let limit = error.downcast_ref::<Timeout>().map(|timeout| timeout.limit);
+        if limit != Some(TimeLimit::Lookup) {
+            return Err(miette!("Expected the lookup to time out, got {error:?}"));
+        }
+
+        Ok(())
+    }
+}
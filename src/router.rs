@@ -0,0 +1,822 @@
+//! Asking the local gateway for its external address
+//!
+//! Home and office routers usually know the public address they were given,
+//! and will tell hosts on the local network about it over one of three
+//! protocols. Asking them means no service outside the local network is
+//! contacted.
+//!
+//! ## Supported protocols
+//!
+//! - NAT-PMP (RFC 6886): a `UDP` request to the gateway on port 5351
+//! - PCP (RFC 6887): a short-lived `MAP` request to the same port, which is
+//!   deleted again once the gateway has answered
+//! - `UPnP` IGD: an SSDP search for the gateway, followed by a SOAP
+//!   `GetExternalIPAddress` call to its `WANIPConnection` service
+//!
+//! All three only report IPv4 addresses, as IPv6 networks rarely use NAT.
+
+use crate::IpVersion::Ipv4;
+use crate::SourceAddresses;
+use crate::http::{self, Url};
+use crate::retry::{IntoTransient, Transient};
+use crate::scope::Scope;
+use crate::stun::new_transaction_id;
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::{FutureExt, future::BoxFuture};
+use miette::{Result, bail, miette};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// The port NAT-PMP and PCP servers listen on
+pub const PORT: u16 = 5351;
+/// The multicast group and port SSDP searches are sent to
+pub const SSDP_ADDR: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250)), 1900);
+
+/// The device type `UPnP` gateways announce themselves as
+const IGD_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// The `UPnP` services that can report the external address
+const WAN_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+
+/// NAT-PMP protocol version
+const NAT_PMP_VERSION: u8 = 0;
+/// PCP protocol version
+const PCP_VERSION: u8 = 2;
+/// PCP `MAP` opcode
+const PCP_MAP: u8 = 1;
+/// Set on the opcode of every NAT-PMP and PCP response
+const RESPONSE_BIT: u8 = 0x80;
+/// Length of a PCP `MAP` request or response
+const PCP_MAP_LEN: usize = 60;
+/// How long the PCP mapping is asked to last, in case deleting it fails
+const PCP_LIFETIME: u32 = 120;
+/// IANA protocol number for UDP, used for the PCP mapping
+const UDP: u8 = 17;
+
+/// How long to wait for the first reply before retransmitting; this doubles
+/// after every attempt, as RFC 6886 describes
+const INITIAL_RTO: Duration = Duration::from_millis(250);
+/// How many times a request is sent before giving up
+const MAX_TRANSMISSIONS: u32 = 4;
+
+/// Ask the gateway for its external address over every protocol at once
+///
+/// # Arguments
+///
+/// * `gateway` - The NAT-PMP and PCP server to ask; defaults to the gateway
+///   of the default route on port 5351
+/// * `search` - Where to send the `UPnP` search, usually `SSDP_ADDR`
+/// * `source` - The local addresses to send the requests from
+///
+/// # Returns
+///
+/// The first external address reported
+///
+/// # Errors
+///
+/// Returns an error listing why each protocol failed if none of them
+/// succeeds
+pub async fn external_ip(
+    gateway: Option<SocketAddr>,
+    search: SocketAddr,
+    source: SourceAddresses,
+) -> Result<Ipv4Addr> {
+    let gateway = gateway.map_or_else(
+        || default_gateway().map(|ip| SocketAddr::new(IpAddr::V4(ip), PORT)),
+        Ok,
+    );
+
+    let lookups: [(&str, BoxFuture<'_, Result<Ipv4Addr>>); 3] = [
+        ("NAT-PMP", {
+            let gateway = gateway.as_ref().map_err(|e| miette!("{e}")).copied();
+            async move { nat_pmp(gateway?, source).await }.boxed()
+        }),
+        ("PCP", {
+            let gateway = gateway.as_ref().map_err(|e| miette!("{e}")).copied();
+            async move { pcp(gateway?, source).await }.boxed()
+        }),
+        ("UPnP", upnp(search, source).boxed()),
+    ];
+
+    let mut pending: FuturesUnordered<_> = lookups
+        .into_iter()
+        .map(|(protocol, lookup)| lookup.map(move |result| (protocol, result)))
+        .collect();
+    let mut errors = Vec::new();
+    while let Some((protocol, result)) = pending.next().await {
+        match result {
+            Ok(ip) => return Ok(ip),
+            Err(e) => errors.push(format!("{protocol}: {e}")),
+        }
+    }
+
+    bail!(
+        "The router did not report its external address ({})",
+        errors.join("; ")
+    )
+}
+
+/// Ask a NAT-PMP server for its external address
+///
+/// # Errors
+///
+/// Returns an error if the gateway does not answer, or answers with an error
+pub async fn nat_pmp(gateway: SocketAddr, source: SourceAddresses) -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind(source.local_addr(gateway.ip())?)
+        .await
+        .into_transient()?;
+    let request = [NAT_PMP_VERSION, 0];
+
+    let ip = transact(&socket, gateway, &request, |response, from| {
+        if from != gateway {
+            return Ok(None);
+        }
+        decode_nat_pmp_response(response)
+    })
+    .await?;
+
+    if ip.is_unspecified() {
+        bail!("{gateway} has no external address");
+    }
+    Ok(ip)
+}
+
+/// Read the external address from a NAT-PMP response
+///
+/// # Returns
+///
+/// `None` if the message is not a response to an external address request
+///
+/// # Errors
+///
+/// Returns an error if the gateway answered with an error
+fn decode_nat_pmp_response(response: &[u8]) -> Result<Option<Ipv4Addr>> {
+    if response.len() < 12 || response[0] != NAT_PMP_VERSION || response[1] != RESPONSE_BIT {
+        return Ok(None);
+    }
+
+    let reason = match u16::from_be_bytes([response[2], response[3]]) {
+        0 => {
+            return Ok(Some(Ipv4Addr::new(
+                response[8],
+                response[9],
+                response[10],
+                response[11],
+            )));
+        }
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "network failure",
+        4 => "out of resources",
+        5 => "unsupported opcode",
+        _ => "unknown error",
+    };
+    bail!("NAT-PMP request failed: {reason}")
+}
+
+/// Ask a PCP server for its external address
+///
+/// PCP has no request that only reports the external address, so a `MAP`
+/// for the socket's own UDP port is requested and deleted again once the
+/// gateway has answered.
+///
+/// # Errors
+///
+/// Returns an error if the gateway does not answer, or answers with an error
+pub async fn pcp(gateway: SocketAddr, source: SourceAddresses) -> Result<Ipv4Addr> {
+    let socket = UdpSocket::bind(source.local_addr(gateway.ip())?)
+        .await
+        .into_transient()?;
+    // Connecting picks the local address the gateway needs to be told about
+    socket.connect(gateway).await.into_transient()?;
+    let local = socket.local_addr().into_transient()?;
+
+    let nonce = new_transaction_id();
+    let request = encode_pcp_map(local, &nonce, PCP_LIFETIME);
+    let ip = transact(&socket, gateway, &request, |response, from| {
+        if from != gateway {
+            return Ok(None);
+        }
+        decode_pcp_map_response(response, &nonce)
+    })
+    .await?;
+
+    // A failed delete leaves the mapping to expire on its own
+    let _ = socket.send(&encode_pcp_map(local, &nonce, 0)).await;
+
+    match ip.to_ipv4_mapped() {
+        Some(ip) if !ip.is_unspecified() => Ok(ip),
+        Some(_) => bail!("{gateway} has no external address"),
+        None => bail!("{gateway} reported an IPv6 external address, {ip}"),
+    }
+}
+
+/// Build a PCP `MAP` request for the UDP port of `local`
+fn encode_pcp_map(local: SocketAddr, nonce: &[u8; 12], lifetime: u32) -> Vec<u8> {
+    let client = match local.ip() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+
+    let mut message = Vec::with_capacity(PCP_MAP_LEN);
+    message.extend_from_slice(&[PCP_VERSION, PCP_MAP, 0, 0]);
+    message.extend_from_slice(&lifetime.to_be_bytes());
+    message.extend_from_slice(&client.octets());
+    message.extend_from_slice(nonce);
+    message.extend_from_slice(&[UDP, 0, 0, 0]);
+    message.extend_from_slice(&local.port().to_be_bytes());
+    // Let the gateway pick the external port and address
+    message.extend_from_slice(&0_u16.to_be_bytes());
+    message.extend_from_slice(&Ipv4Addr::UNSPECIFIED.to_ipv6_mapped().octets());
+    message
+}
+
+/// Read the assigned external address from a PCP `MAP` response
+///
+/// # Returns
+///
+/// `None` if the message is not a response to the request with `nonce`
+///
+/// # Errors
+///
+/// Returns an error if the gateway answered with an error
+fn decode_pcp_map_response(response: &[u8], nonce: &[u8; 12]) -> Result<Option<Ipv6Addr>> {
+    // Servers that only speak NAT-PMP answer with its version number
+    if response.len() >= 4 && response[0] == NAT_PMP_VERSION && response[1] & RESPONSE_BIT != 0 {
+        bail!("The gateway only supports NAT-PMP");
+    }
+    if response.len() < 4 || response[0] != PCP_VERSION || response[1] != (RESPONSE_BIT | PCP_MAP) {
+        return Ok(None);
+    }
+
+    let reason = match response[3] {
+        0 => {
+            if response.len() < PCP_MAP_LEN || response[24..36] != *nonce {
+                return Ok(None);
+            }
+            let mut ip = [0; 16];
+            ip.copy_from_slice(&response[44..60]);
+            return Ok(Some(Ipv6Addr::from(ip)));
+        }
+        1 => "unsupported version",
+        2 => "not authorized",
+        3 => "malformed request",
+        4 => "unsupported opcode",
+        7 => "network failure",
+        8 => "no resources",
+        9 => "unsupported protocol",
+        10 => "user exceeded quota",
+        11 => "cannot provide an external address",
+        12 => "address mismatch",
+        _ => "unknown error",
+    };
+    bail!("PCP request failed: {reason}")
+}
+
+/// Find a `UPnP` gateway and ask it for its external address
+///
+/// # Arguments
+///
+/// * `search` - Where to send the SSDP search, usually `SSDP_ADDR`
+/// * `source` - The local addresses to send the requests from
+///
+/// # Errors
+///
+/// Returns an error if no gateway answers the search, its description has
+/// no WAN connection service, the service is on another host, or the
+/// service does not report an address
+pub async fn upnp(search: SocketAddr, source: SourceAddresses) -> Result<Ipv4Addr> {
+    let location = ssdp_search(search, source).await?;
+    let description = http::get(&location, Ipv4, source, None).await?;
+    let (service, control_url) = find_wan_service(&description, &location)?;
+    if control_url.host != location.host {
+        bail!("{location} describes a WAN connection service on another host, at {control_url}");
+    }
+
+    let action = format!("\"{service}#GetExternalIPAddress\"");
+    let body = format!(
+        "<?xml version=\"1.0\"?>\r\n\
+         <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+         s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+         <s:Body><u:GetExternalIPAddress xmlns:u=\"{service}\"/></s:Body>\
+         </s:Envelope>\r\n"
+    );
+    let response = http::post(
+        &control_url,
+        Ipv4,
+        source,
+        &[
+            ("Content-Type", "text/xml; charset=\"utf-8\""),
+            ("SOAPAction", &action),
+        ],
+        &body,
+    )
+    .await?;
+
+    let ip = xml_text(&response, "NewExternalIPAddress")
+        .filter(|ip| !ip.is_empty())
+        .ok_or_else(|| miette!("{control_url} did not report an external address"))?;
+    ip.parse()
+        .map_err(|_| miette!("{control_url} reported an invalid external address '{ip}'"))
+}
+
+/// Search for a `UPnP` gateway and return the URL of its description
+///
+/// Only answers from a gateway on the local network are taken, and only if
+/// it serves its description itself, so that another device's answer can't
+/// send the lookup somewhere else.
+async fn ssdp_search(search: SocketAddr, source: SourceAddresses) -> Result<Url> {
+    let socket = UdpSocket::bind(source.local_addr(search.ip())?)
+        .await
+        .into_transient()?;
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {search}\r\nMAN: \"ssdp:discover\"\r\nMX: 1\r\nST: {IGD_DEVICE}\r\n\r\n"
+    );
+
+    // Every device on the network may answer a multicast search
+    transact(&socket, search, request.as_bytes(), |response, from| {
+        Ok(ssdp_location(&String::from_utf8_lossy(response))
+            .and_then(|url| url.parse::<Url>().ok())
+            .filter(|url| is_served_by(url, from.ip())))
+    })
+    .await
+}
+
+/// Read the `LOCATION` header from a gateway's response to an SSDP search
+///
+/// Responses whose `ST` and `USN` headers don't both name an Internet Gateway
+/// Device, such as those from printers or media servers, are ignored.
+fn ssdp_location(response: &str) -> Option<&str> {
+    let mut lines = response.lines();
+    if !lines.next()?.contains(" 200 ") {
+        return None;
+    }
+
+    let headers: Vec<_> = lines
+        .filter_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            Some((name.trim(), value.trim()))
+        })
+        .collect();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    };
+
+    let usn_suffix = format!("::{IGD_DEVICE}");
+    let is_gateway = header("ST") == Some(IGD_DEVICE)
+        && header("USN").is_some_and(|usn| usn.ends_with(&usn_suffix));
+    is_gateway.then(|| header("LOCATION")).flatten()
+}
+
+/// Whether `url` is on `device`, and `device` is on the local network
+fn is_served_by(url: &Url, device: IpAddr) -> bool {
+    url.host.parse::<IpAddr>().is_ok_and(|host| host == device)
+        && matches!(
+            Scope::of(device),
+            Scope::Loopback | Scope::LinkLocal | Scope::UniqueLocal | Scope::Private
+        )
+}
+
+/// Find the WAN connection service in a gateway's description
+///
+/// # Returns
+///
+/// The service type and the URL to send its SOAP requests to
+fn find_wan_service(description: &str, location: &Url) -> Result<(String, Url)> {
+    let base = xml_text(description, "URLBase")
+        .and_then(|base| base.parse::<Url>().ok())
+        .unwrap_or_else(|| location.clone());
+
+    description
+        .split("<service>")
+        .skip(1)
+        .find_map(|service| {
+            let service_type = xml_text(service, "serviceType")?;
+            if !WAN_SERVICES.iter().any(|wan| service_type.starts_with(wan)) {
+                return None;
+            }
+            let control = xml_text(service, "controlURL")?;
+            Some((service_type.to_string(), resolve_url(&base, control)))
+        })
+        .ok_or_else(|| miette!("{location} does not describe a WAN connection service"))
+}
+
+/// Resolve a URL from a `UPnP` description against the description's base
+fn resolve_url(base: &Url, url: &str) -> Url {
+    if let Ok(url) = url.parse() {
+        return url;
+    }
+
+    let path = if url.starts_with('/') {
+        url.to_string()
+    } else {
+        format!("/{url}")
+    };
+    Url {
+        path,
+        ..base.clone()
+    }
+}
+
+/// The text inside the first `<tag>`, ignoring any namespace prefix
+fn xml_text<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.match_indices(tag).find_map(|(index, _)| {
+        let before = xml[..index].rsplit_once('<')?.1;
+        let after = xml[index + tag.len()..].strip_prefix('>')?;
+        (!before.starts_with('/') && !before.contains(['>', ' '])).then_some(after)
+    })?;
+    let end = start.find("</")?;
+    Some(start[..end].trim())
+}
+
+/// Send a request and wait for a response `accept` takes, retransmitting as
+/// needed
+///
+/// `accept` is given every datagram that arrives along with its sender, and
+/// returns `None` to keep waiting.
+async fn transact<T>(
+    socket: &UdpSocket,
+    to: SocketAddr,
+    request: &[u8],
+    mut accept: impl FnMut(&[u8], SocketAddr) -> Result<Option<T>>,
+) -> Result<T> {
+    let mut buffer = [0; 2048];
+    let mut rto = INITIAL_RTO;
+
+    for _ in 0..MAX_TRANSMISSIONS {
+        socket.send_to(request, to).await.into_transient()?;
+
+        let deadline = tokio::time::Instant::now() + rto;
+        while let Ok(received) =
+            tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+        {
+            let (len, from) = received.into_transient()?;
+            if let Some(response) = accept(&buffer[..len], from)? {
+                return Ok(response);
+            }
+        }
+
+        rto *= 2;
+    }
+
+    Err(Transient::new(format!("Timed out waiting for a response from {to}")).into())
+}
+
+/// Find the gateway of the default IPv4 route
+///
+/// # Errors
+///
+/// Returns an error if there is no default route, or the routing table
+/// cannot be read on this platform
+pub fn default_gateway() -> Result<Ipv4Addr> {
+    #[cfg(target_os = "linux")]
+    {
+        let table = std::fs::read_to_string("/proc/net/route")
+            .map_err(|e| miette!("Failed to read the routing table: {e}"))?;
+        parse_route_table(&table).ok_or_else(|| miette!("There is no default IPv4 route"))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        bail!("Can't read the default gateway on this platform, so it must be given")
+    }
+}
+
+/// Find the gateway of the default route with the lowest metric in the
+/// contents of `/proc/net/route`
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_route_table(table: &str) -> Option<Ipv4Addr> {
+    /// Route is usable
+    const RTF_UP: u16 = 0x1;
+    /// Route goes through a gateway
+    const RTF_GATEWAY: u16 = 0x2;
+
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let hex = |index: usize| u32::from_str_radix(fields.get(index)?, 16).ok();
+            let (destination, gateway, mask) = (hex(1)?, hex(2)?, hex(7)?);
+            let flags = u16::from_str_radix(fields.get(3)?, 16).ok()?;
+            let metric: u32 = fields.get(6)?.parse().ok()?;
+
+            let up = flags & (RTF_UP | RTF_GATEWAY) == RTF_UP | RTF_GATEWAY;
+            // Addresses are printed as the raw bytes of a network-order integer
+            (up && destination == 0 && mask == 0)
+                .then(|| (metric, Ipv4Addr::from(gateway.to_ne_bytes())))
+        })
+        .min_by_key(|(metric, _)| *metric)
+        .map(|(_, gateway)| gateway)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miette::IntoDiagnostic;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    type TestResult = Result<()>;
+
+    const EXTERNAL: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 7);
+
+    /// Answer NAT-PMP and PCP requests as a gateway with `EXTERNAL` would
+    async fn gateway_stand_in(pcp: bool) -> Result<SocketAddr> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .into_diagnostic()?;
+        let addr = socket.local_addr().into_diagnostic()?;
+
+        tokio::spawn(serve_gateway(socket, pcp));
+
+        Ok(addr)
+    }
+
+    async fn serve_gateway(socket: UdpSocket, pcp: bool) -> std::io::Result<()> {
+        let mut buffer = [0; 1100];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buffer).await?;
+            let request = &buffer[..len];
+
+            let response = match request[0] {
+                NAT_PMP_VERSION => {
+                    let mut response = vec![NAT_PMP_VERSION, RESPONSE_BIT, 0, 0, 0, 0, 0, 1];
+                    response.extend_from_slice(&EXTERNAL.octets());
+                    response
+                }
+                PCP_VERSION if pcp => {
+                    let mut response = vec![PCP_VERSION, RESPONSE_BIT | PCP_MAP, 0, 0];
+                    response.extend_from_slice(&request[4..8]);
+                    response.extend_from_slice(&[0; 16]);
+                    response.extend_from_slice(&request[24..44]);
+                    response.extend_from_slice(&EXTERNAL.to_ipv6_mapped().octets());
+                    response
+                }
+                // A NAT-PMP server rejects other versions
+                _ => vec![NAT_PMP_VERSION, RESPONSE_BIT | request[1], 0, 1, 0, 0, 0, 1],
+            };
+            socket.send_to(&response, peer).await?;
+        }
+    }
+
+    /// Answer an SSDP search, then serve a gateway description and SOAP call
+    ///
+    /// The gateway's answer comes after one from a device that isn't a
+    /// gateway, and one that points somewhere other than the gateway, both
+    /// of which lead nowhere.
+    async fn upnp_stand_in(description: &'static str) -> Result<SocketAddr> {
+        let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .into_diagnostic()?;
+        let search = socket.local_addr().into_diagnostic()?;
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .into_diagnostic()?;
+        let http = listener.local_addr().into_diagnostic()?;
+
+        tokio::spawn(async move {
+            let mut buffer = [0; 1024];
+            let (_, peer) = socket.recv_from(&mut buffer).await?;
+            let other_host = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), http.port());
+            for (device, location) in [
+                ("urn:schemas-upnp-org:device:MediaServer:1", http),
+                (IGD_DEVICE, other_host),
+                (IGD_DEVICE, http),
+            ] {
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nST: {device}\r\nUSN: uuid:5f0e::{device}\r\nLocation: http://{location}/rootDesc.xml\r\n\r\n"
+                );
+                socket.send_to(response.as_bytes(), peer).await?;
+            }
+            std::io::Result::Ok(())
+        });
+        tokio::spawn(serve_upnp(listener, description));
+
+        Ok(search)
+    }
+
+    async fn serve_upnp(listener: TcpListener, description: &'static str) -> std::io::Result<()> {
+        loop {
+            let (mut stream, _) = listener.accept().await?;
+            let mut request = vec![0; 4096];
+            let len = stream.read(&mut request).await?;
+            let request = String::from_utf8_lossy(&request[..len]);
+
+            let body = if request.starts_with("GET /rootDesc.xml ") {
+                description.to_string()
+            } else if request.starts_with("POST /ctl/IPConn ")
+                && request.contains("#GetExternalIPAddress")
+            {
+                format!(
+                    "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                     <NewExternalIPAddress>{EXTERNAL}</NewExternalIPAddress>\
+                     </u:GetExternalIPAddressResponse></s:Body></s:Envelope>"
+                )
+            } else {
+                stream.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await?;
+                continue;
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(response.as_bytes()).await?;
+        }
+    }
+
+    const DESCRIPTION: &str = "<?xml version=\"1.0\"?>
+<root xmlns=\"urn:schemas-upnp-org:device-1-0\">
+  <device>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+        <controlURL>/ctl/L3F</controlURL>
+      </service>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+        <controlURL>/ctl/IPConn</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>";
+
+    #[tokio::test]
+    async fn asks_the_gateway_over_nat_pmp() -> TestResult {
+        let gateway = gateway_stand_in(false).await?;
+        let ip = nat_pmp(gateway, SourceAddresses::default()).await?;
+
+        if ip != EXTERNAL {
+            return Err(miette!("Expected {EXTERNAL}, got {ip}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn asks_the_gateway_over_pcp() -> TestResult {
+        let gateway = gateway_stand_in(true).await?;
+        let ip = pcp(gateway, SourceAddresses::default()).await?;
+
+        if ip != EXTERNAL {
+            return Err(miette!("Expected {EXTERNAL}, got {ip}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pcp_fails_against_a_nat_pmp_only_gateway() -> TestResult {
+        let gateway = gateway_stand_in(false).await?;
+
+        if let Ok(ip) = pcp(gateway, SourceAddresses::default()).await {
+            return Err(miette!("Expected PCP to fail, got {ip}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn asks_the_gateway_over_upnp() -> TestResult {
+        let search = upnp_stand_in(DESCRIPTION).await?;
+        let ip = upnp(search, SourceAddresses::default()).await?;
+
+        if ip != EXTERNAL {
+            return Err(miette!("Expected {EXTERNAL}, got {ip}"));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn external_ip_uses_whichever_protocol_answers() -> TestResult {
+        // Nothing answers the UPnP search, which is sent to the NAT-PMP port
+        let gateway = gateway_stand_in(false).await?;
+        let ip = external_ip(Some(gateway), gateway, SourceAddresses::default()).await?;
+
+        if ip != EXTERNAL {
+            return Err(miette!("Expected {EXTERNAL}, got {ip}"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_takes_the_location_of_a_gateway() -> TestResult {
+        let location = "http://192.168.1.1:5000/rootDesc.xml";
+        for (response, expected) in [
+            (
+                format!(
+                    "HTTP/1.1 200 OK\r\nST: {IGD_DEVICE}\r\nUSN: uuid:5f0e::{IGD_DEVICE}\r\nLOCATION: {location}\r\n\r\n"
+                ),
+                Some(location),
+            ),
+            (
+                format!(
+                    "HTTP/1.1 200 OK\r\nST: upnp:rootdevice\r\nUSN: uuid:5f0e::upnp:rootdevice\r\nLOCATION: {location}\r\n\r\n"
+                ),
+                None,
+            ),
+            (
+                format!("HTTP/1.1 200 OK\r\nST: {IGD_DEVICE}\r\nLOCATION: {location}\r\n\r\n"),
+                None,
+            ),
+            (
+                format!(
+                    "HTTP/1.1 404 Not Found\r\nST: {IGD_DEVICE}\r\nUSN: uuid:5f0e::{IGD_DEVICE}\r\nLOCATION: {location}\r\n\r\n"
+                ),
+                None,
+            ),
+        ] {
+            let actual = ssdp_location(&response);
+            if actual != expected {
+                return Err(miette!(
+                    "Expected {expected:?} from {response:?}, got {actual:?}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn only_fetches_descriptions_from_the_device_on_the_local_network() -> TestResult {
+        for (url, device, expected) in [
+            (
+                "http://192.168.1.1:5000/rootDesc.xml",
+                [192, 168, 1, 1],
+                true,
+            ),
+            (
+                "http://192.168.1.9:5000/rootDesc.xml",
+                [192, 168, 1, 1],
+                false,
+            ),
+            (
+                "http://router.lan:5000/rootDesc.xml",
+                [192, 168, 1, 1],
+                false,
+            ),
+            (
+                "http://203.0.113.9:5000/rootDesc.xml",
+                [203, 0, 113, 9],
+                false,
+            ),
+        ] {
+            let actual = is_served_by(&url.parse()?, IpAddr::from(device));
+            if actual != expected {
+                return Err(miette!(
+                    "Expected {url} answered from {device:?} to be fetched: {expected}"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn resolves_control_urls_against_the_description() -> TestResult {
+        let location: Url = "http://192.168.1.1:5000/rootDesc.xml".parse()?;
+        let (service, control) = find_wan_service(DESCRIPTION, &location)?;
+
+        if service != "urn:schemas-upnp-org:service:WANIPConnection:1" {
+            return Err(miette!(
+                "Expected the WANIPConnection service, got {service}"
+            ));
+        }
+        if control.to_string() != "http://192.168.1.1:5000/ctl/IPConn" {
+            return Err(miette!(
+                "Expected the control URL on the gateway, got {control}"
+            ));
+        }
+
+        Ok(())
+    }
+
+    // The kernel prints addresses in host byte order
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn finds_the_default_route() -> TestResult {
+        let table =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+eth0\t00000000\t0100000A\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0000000A\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+";
+        let expected = Ipv4Addr::new(10, 0, 0, 1);
+        let actual = parse_route_table(table);
+
+        if actual != Some(expected) {
+            return Err(miette!("Expected {expected}, got {actual:?}"));
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! Classifying addresses by how far they reach
+//!
+//! Which addresses are worth printing depends on what they're for: a server
+//! wants its globally routable addresses, a LAN service its private ones. A
+//! `Scope` says which special-purpose range, if any, an address falls in.
+//!
+//! IPv4-mapped IPv6 addresses, such as `::ffff:192.168.1.56`, are classified
+//! as the IPv4 address they carry.
+
+use core::fmt;
+use core::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The range an address is in, from the most to the least local
+#[derive(Copy, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Scope {
+    /// Only reachable from this machine: `127.0.0.0/8` or `::1`
+    Loopback,
+    /// Only reachable on the local link: `169.254.0.0/16` or `fe80::/10`
+    LinkLocal,
+    /// IPv6 unique local addresses, for use within a site: `fc00::/7`
+    UniqueLocal,
+    /// IPv4 private networks (RFC 1918): `10.0.0.0/8`, `172.16.0.0/12` and
+    /// `192.168.0.0/16`
+    Private,
+    /// The space carriers share behind carrier-grade NAT (RFC 6598):
+    /// `100.64.0.0/10`
+    Shared,
+    /// Reserved for examples: `192.0.2.0/24`, `198.51.100.0/24`,
+    /// `203.0.113.0/24`, `2001:db8::/32` and `3fff::/20`
+    Documentation,
+    /// Multicast groups: `224.0.0.0/4` or `ff00::/8`
+    Multicast,
+    /// Any other unicast address, routable on the internet
+    Global,
+}
+
+impl Scope {
+    /// Find the scope of an address
+    #[must_use]
+    pub const fn of(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => Self::of_ipv4(ip),
+            IpAddr::V6(ip) => Self::of_ipv6(ip),
+        }
+    }
+
+    /// Find the scope of an IPv4 address
+    const fn of_ipv4(ip: Ipv4Addr) -> Self {
+        let [first, second, third, _] = ip.octets();
+
+        if ip.is_loopback() {
+            Self::Loopback
+        } else if ip.is_multicast() {
+            Self::Multicast
+        } else if ip.is_link_local() {
+            Self::LinkLocal
+        } else if ip.is_private() {
+            Self::Private
+        } else if crate::cgnat::is_shared_address(ip) {
+            Self::Shared
+        } else if matches!(
+            (first, second, third),
+            (192, 0, 2) | (198, 51, 100) | (203, 0, 113)
+        ) {
+            Self::Documentation
+        } else {
+            Self::Global
+        }
+    }
+
+    /// Find the scope of an IPv6 address
+    const fn of_ipv6(ip: Ipv6Addr) -> Self {
+        let [first, second, ..] = ip.segments();
+        let documentation_32 = first == 0x2001 && second == 0x0db8;
+        let documentation_20 = first == 0x3fff && second & 0xf000 == 0;
+
+        if ip.is_loopback() {
+            Self::Loopback
+        } else if ip.is_multicast() {
+            Self::Multicast
+        } else if ip.is_unicast_link_local() {
+            Self::LinkLocal
+        } else if ip.is_unique_local() {
+            Self::UniqueLocal
+        } else if documentation_32 || documentation_20 {
+            Self::Documentation
+        } else {
+            Self::Global
+        }
+    }
+
+    /// The name used for the scope on the command line
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Loopback => "loopback",
+            Self::LinkLocal => "link-local",
+            Self::UniqueLocal => "unique-local",
+            Self::Private => "private",
+            Self::Shared => "shared",
+            Self::Documentation => "documentation",
+            Self::Multicast => "multicast",
+            Self::Global => "global",
+        }
+    }
+}
+
+impl Display for Scope {
+    /// Format the scope as its command line name, such as `link-local`
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// Implemented by hand so that the variant docs don't switch the command line
+// help over to its long layout
+impl clap::ValueEnum for Scope {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Self::Loopback,
+            Self::LinkLocal,
+            Self::UniqueLocal,
+            Self::Private,
+            Self::Shared,
+            Self::Documentation,
+            Self::Multicast,
+            Self::Global,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        Some(clap::builder::PossibleValue::new(self.name()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miette::{IntoDiagnostic, Result, miette};
+
+    type TestResult = Result<()>;
+
+    #[test]
+    fn classifies_each_special_range() -> TestResult {
+        for (ip, expected) in [
+            ("127.0.0.1", Scope::Loopback),
+            ("::1", Scope::Loopback),
+            ("169.254.10.1", Scope::LinkLocal),
+            ("fe80::4", Scope::LinkLocal),
+            ("fd00::2", Scope::UniqueLocal),
+            ("fc12::1", Scope::UniqueLocal),
+            ("10.1.2.3", Scope::Private),
+            ("172.31.0.1", Scope::Private),
+            ("192.168.1.56", Scope::Private),
+            ("::ffff:192.168.1.56", Scope::Private),
+            ("100.72.3.4", Scope::Shared),
+            ("192.0.2.2", Scope::Documentation),
+            ("198.51.100.9", Scope::Documentation),
+            ("203.0.113.7", Scope::Documentation),
+            ("2001:db8::1", Scope::Documentation),
+            ("3fff:1::1", Scope::Documentation),
+            ("3fff:fff::1", Scope::Documentation),
+            ("3ff0::1", Scope::Global),
+            ("3ffe::1", Scope::Global),
+            ("3fff:1000::1", Scope::Global),
+            ("224.0.0.251", Scope::Multicast),
+            ("ff02::fb", Scope::Multicast),
+            ("207.105.7.192", Scope::Global),
+            ("172.32.0.1", Scope::Global),
+            ("2a00:1450:4009::1", Scope::Global),
+        ] {
+            let actual = Scope::of(ip.parse().into_diagnostic()?);
+            if actual != expected {
+                return Err(miette!("Expected {ip} to be {expected}, got {actual}"));
+            }
+        }
+
+        Ok(())
+    }
+}
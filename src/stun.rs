@@ -0,0 +1,501 @@
+//! A minimal STUN client for discovering UDP-mapped addresses
+//!
+//! STUN (RFC 5389, updated by RFC 8489) servers reply to a Binding Request
+//! with the address and port the request arrived from. Behind a NAT this is
+//! the public mapping the NAT created for our UDP socket. This is what peers
+//! in a voice or video call will see, and it can differ from the address used
+//! for DNS or HTTP traffic.
+//!
+//! Only the parts of the protocol needed to send a Binding Request and read
+//! back the mapped address are implemented, along with the `CHANGE-REQUEST`
+//! and `OTHER-ADDRESS` attributes RFC 5780 uses to discover NAT behaviour.
+//! Requests are retransmitted over UDP as the RFC describes, but
+//! authentication is not supported.
+
+use crate::IpVersion::{Ipv4, Ipv6};
+use crate::retry::{IntoTransient, Transient};
+use crate::{IpVersion, SourceAddresses};
+use miette::{Result, bail, miette};
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// The fixed value in every STUN message header
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+/// Binding Request message type
+const BINDING_REQUEST: u16 = 0x0001;
+/// Binding Success Response message type
+const BINDING_SUCCESS: u16 = 0x0101;
+/// Binding Error Response message type
+const BINDING_ERROR: u16 = 0x0111;
+/// MAPPED-ADDRESS attribute, used by pre-RFC 5389 servers
+const MAPPED_ADDRESS: u16 = 0x0001;
+/// XOR-MAPPED-ADDRESS attribute
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+/// ERROR-CODE attribute
+const ERROR_CODE: u16 = 0x0009;
+/// CHANGE-REQUEST attribute, asking the server to answer from another address
+const CHANGE_REQUEST: u16 = 0x0003;
+/// CHANGED-ADDRESS attribute, the RFC 3489 name for OTHER-ADDRESS
+const CHANGED_ADDRESS: u16 = 0x0005;
+/// OTHER-ADDRESS attribute
+const OTHER_ADDRESS: u16 = 0x802C;
+/// Length of the STUN message header
+const HEADER_LEN: usize = 20;
+/// The port STUN servers listen on when none is given
+pub const DEFAULT_PORT: u16 = 3478;
+
+/// How long to wait for the first reply before retransmitting; this doubles
+/// after every attempt
+pub(crate) const INITIAL_RTO: Duration = Duration::from_millis(500);
+/// How many times a request is sent before giving up
+pub(crate) const MAX_TRANSMISSIONS: u32 = 4;
+
+/// A STUN transaction ID, unique to each request
+pub type TransactionId = [u8; 12];
+
+/// The parts of a Binding Success Response we understand
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct BindingResponse {
+    /// The address and port the server saw the request come from
+    pub mapped_address: Option<SocketAddr>,
+    /// The server's alternate address, for servers that support RFC 5780
+    pub other_address: Option<SocketAddr>,
+}
+
+/// Asks the server to send its response from a different IP address, port,
+/// or both
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct ChangeRequest {
+    /// Answer from the server's alternate IP address
+    pub ip: bool,
+    /// Answer from the server's alternate port
+    pub port: bool,
+}
+
+impl ChangeRequest {
+    /// Whether the server is asked to answer from anywhere but the address
+    /// the request was sent to
+    #[must_use]
+    pub const fn is_change(self) -> bool {
+        self.ip || self.port
+    }
+}
+
+/// Ask a STUN server which address and port our request came from
+///
+/// A fresh UDP socket is bound for the request, using the same IP version as
+/// the server address.
+///
+/// # Arguments
+///
+/// * `server` - The address of the STUN server
+/// * `source` - The local addresses to send the request from
+///
+/// # Returns
+///
+/// The public address and port the server saw the request come from
+///
+/// # Errors
+///
+/// Returns an error if the socket cannot be bound, the server does not
+/// respond after several retransmissions, or the response cannot be parsed
+pub async fn binding_request(server: SocketAddr, source: SourceAddresses) -> Result<SocketAddr> {
+    let local = source.local_addr(server.ip())?;
+    let socket = UdpSocket::bind(local).await.into_transient()?;
+
+    transact(&socket, server)
+        .await?
+        .mapped_address
+        .ok_or_else(|| miette!("{server} did not include a mapped address in its response"))
+}
+
+/// Resolve a STUN server's `host:port` to addresses of one IP version
+///
+/// # Arguments
+///
+/// * `server` - The server as `host:port`; the port defaults to 3478
+/// * `strategy` - The IP version to keep
+///
+/// # Errors
+///
+/// Returns an error if the host cannot be resolved
+pub async fn resolve(server: &str, strategy: IpVersion) -> Result<Vec<SocketAddr>> {
+    let addrs: Vec<SocketAddr> = if let Ok(addr) = server.parse::<SocketAddr>() {
+        vec![addr]
+    } else if let Ok(ip) = server.trim_matches(['[', ']']).parse::<IpAddr>() {
+        vec![SocketAddr::new(ip, DEFAULT_PORT)]
+    } else if server.contains(':') {
+        tokio::net::lookup_host(server)
+            .await
+            .into_transient()?
+            .collect()
+    } else {
+        tokio::net::lookup_host((server, DEFAULT_PORT))
+            .await
+            .into_transient()?
+            .collect()
+    };
+
+    Ok(addrs
+        .into_iter()
+        .filter(|addr| match strategy {
+            Ipv4 => addr.is_ipv4(),
+            Ipv6 => addr.is_ipv6(),
+        })
+        .collect())
+}
+
+/// Send a Binding Request and wait for the matching response, retransmitting
+/// as needed
+///
+/// # Arguments
+///
+/// * `socket` - The socket to send from
+/// * `server` - The address of the STUN server
+///
+/// # Errors
+///
+/// Returns an error if no response arrives, or the server responds with an
+/// error
+pub async fn transact(socket: &UdpSocket, server: SocketAddr) -> Result<BindingResponse> {
+    let (response, _) = transact_with(socket, server, ChangeRequest::default(), MAX_TRANSMISSIONS)
+        .await?
+        .ok_or_else(|| {
+            Transient::new(format!(
+                "Timed out waiting for a STUN response from {server}"
+            ))
+        })?;
+    Ok(response)
+}
+
+/// Send a Binding Request that may ask the server to answer from another
+/// address, and wait for the matching response
+///
+/// # Arguments
+///
+/// * `socket` - The socket to send from
+/// * `server` - The address of the STUN server
+/// * `change` - Where the server should answer from
+/// * `transmissions` - How many times to send the request before giving up
+///
+/// # Returns
+///
+/// The response and the address it came from, or `None` if no response
+/// arrived
+///
+/// # Errors
+///
+/// Returns an error if the socket fails, or the server responds with an
+/// error
+pub async fn transact_with(
+    socket: &UdpSocket,
+    server: SocketAddr,
+    change: ChangeRequest,
+    transmissions: u32,
+) -> Result<Option<(BindingResponse, SocketAddr)>> {
+    let transaction_id = new_transaction_id();
+    let request = encode_change_request(&transaction_id, change);
+    let mut buffer = [0; 1500];
+    let mut rto = INITIAL_RTO;
+
+    for _ in 0..transmissions {
+        socket.send_to(&request, server).await.into_transient()?;
+
+        let deadline = tokio::time::Instant::now() + rto;
+        // Ignore stray packets until the deadline for this transmission
+        while let Ok(received) =
+            tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+        {
+            let (len, from) = received.into_transient()?;
+            // A changed response comes from elsewhere, so only the ID can be checked
+            if from != server && !change.is_change() {
+                continue;
+            }
+            if let Some(response) = decode_binding_response(&buffer[..len], &transaction_id)? {
+                return Ok(Some((response, from)));
+            }
+        }
+
+        rto *= 2;
+    }
+
+    Ok(None)
+}
+
+/// Generate a random transaction ID
+pub(crate) fn new_transaction_id() -> TransactionId {
+    // RandomState is seeded from the operating system's random source
+    let mut id = [0; 12];
+    let high = RandomState::new().hash_one(0_u8).to_be_bytes();
+    let low = RandomState::new().hash_one(1_u8).to_be_bytes();
+    id[..8].copy_from_slice(&high);
+    id[8..].copy_from_slice(&low[..4]);
+    id
+}
+
+/// Build a Binding Request with no attributes
+#[must_use]
+pub fn encode_binding_request(transaction_id: &TransactionId) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_LEN);
+    message.extend_from_slice(&BINDING_REQUEST.to_be_bytes());
+    message.extend_from_slice(&0_u16.to_be_bytes());
+    message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    message.extend_from_slice(transaction_id);
+    message
+}
+
+/// Build a Binding Request with a CHANGE-REQUEST attribute, or with no
+/// attributes if nothing is to be changed
+#[must_use]
+pub fn encode_change_request(transaction_id: &TransactionId, change: ChangeRequest) -> Vec<u8> {
+    let mut message = encode_binding_request(transaction_id);
+    if !change.is_change() {
+        return message;
+    }
+
+    let flags = u32::from(change.ip) << 2 | u32::from(change.port) << 1;
+    message[2..4].copy_from_slice(&8_u16.to_be_bytes());
+    message.extend_from_slice(&CHANGE_REQUEST.to_be_bytes());
+    message.extend_from_slice(&4_u16.to_be_bytes());
+    message.extend_from_slice(&flags.to_be_bytes());
+    message
+}
+
+/// Parse a Binding Response
+///
+/// # Arguments
+///
+/// * `message` - The raw message received from the server
+/// * `transaction_id` - The ID of the request we are waiting on a reply to
+///
+/// # Returns
+///
+/// `None` if the message is not a response to this transaction, otherwise
+/// the parsed response
+///
+/// # Errors
+///
+/// Returns an error if the server sent an error response to our request
+pub fn decode_binding_response(
+    message: &[u8],
+    transaction_id: &TransactionId,
+) -> Result<Option<BindingResponse>> {
+    if message.len() < HEADER_LEN
+        || message[4..8] != MAGIC_COOKIE.to_be_bytes()
+        || message[8..HEADER_LEN] != *transaction_id
+    {
+        return Ok(None);
+    }
+
+    let message_type = u16::from_be_bytes([message[0], message[1]]);
+    let length = usize::from(u16::from_be_bytes([message[2], message[3]]));
+    let Some(mut attributes) = message.get(HEADER_LEN..HEADER_LEN + length) else {
+        return Ok(None);
+    };
+
+    let mut response = BindingResponse::default();
+    let mut mapped_address = None;
+    let mut error = None;
+
+    while attributes.len() >= 4 {
+        let kind = u16::from_be_bytes([attributes[0], attributes[1]]);
+        let len = usize::from(u16::from_be_bytes([attributes[2], attributes[3]]));
+        let Some(value) = attributes.get(4..4 + len) else {
+            break;
+        };
+
+        match kind {
+            XOR_MAPPED_ADDRESS => {
+                response.mapped_address = decode_address(value, Some(transaction_id));
+            }
+            MAPPED_ADDRESS => mapped_address = decode_address(value, None),
+            OTHER_ADDRESS | CHANGED_ADDRESS => {
+                response.other_address = decode_address(value, None);
+            }
+            ERROR_CODE if value.len() >= 4 => {
+                let code = u16::from(value[2] & 0x7) * 100 + u16::from(value[3]);
+                let reason = String::from_utf8_lossy(&value[4..]).trim().to_string();
+                error = Some(format!("{code} {reason}"));
+            }
+            _ => {}
+        }
+
+        // Attributes are padded to a multiple of four bytes
+        let padded = (4 + len).next_multiple_of(4);
+        attributes = attributes.get(padded..).unwrap_or_default();
+    }
+
+    match message_type {
+        BINDING_SUCCESS => {
+            // Only fall back to the un-obfuscated address for old servers
+            response.mapped_address = response.mapped_address.or(mapped_address);
+            Ok(Some(response))
+        }
+        BINDING_ERROR => bail!(
+            "STUN server returned an error: {}",
+            error.unwrap_or_else(|| "unknown error".to_string())
+        ),
+        _ => Ok(None),
+    }
+}
+
+/// Parse a (XOR-)MAPPED-ADDRESS attribute value
+///
+/// When `transaction_id` is given the address is XOR-obfuscated, as it is in
+/// XOR-MAPPED-ADDRESS.
+fn decode_address(value: &[u8], transaction_id: Option<&TransactionId>) -> Option<SocketAddr> {
+    let family = *value.get(1)?;
+    let mut port = u16::from_be_bytes([*value.get(2)?, *value.get(3)?]);
+
+    // The XOR key is the magic cookie followed by the transaction ID
+    let mut key = [0; 16];
+    if let Some(transaction_id) = transaction_id {
+        key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(transaction_id);
+        port ^= u16::from_be_bytes([key[0], key[1]]);
+    }
+
+    let ip = match family {
+        0x01 => {
+            let mut octets: [u8; 4] = value.get(4..8)?.try_into().ok()?;
+            octets.iter_mut().zip(key).for_each(|(byte, k)| *byte ^= k);
+            IpAddr::from(octets)
+        }
+        0x02 => {
+            let mut octets: [u8; 16] = value.get(4..20)?.try_into().ok()?;
+            octets.iter_mut().zip(key).for_each(|(byte, k)| *byte ^= k);
+            IpAddr::from(octets)
+        }
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miette::IntoDiagnostic;
+    use std::net::Ipv4Addr;
+
+    type TestResult = Result<()>;
+
+    /// Build a Binding Success Response with a single address attribute
+    fn success(transaction_id: &TransactionId, kind: u16, addr: SocketAddr) -> Vec<u8> {
+        let xor = kind == XOR_MAPPED_ADDRESS;
+        let mut key = [0; 16];
+        key[..4].copy_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        key[4..].copy_from_slice(transaction_id);
+
+        let mut value = vec![0];
+        let port = if xor {
+            addr.port() ^ u16::from_be_bytes([key[0], key[1]])
+        } else {
+            addr.port()
+        };
+        let octets = match addr.ip() {
+            IpAddr::V4(ip) => {
+                value.push(0x01);
+                ip.octets().to_vec()
+            }
+            IpAddr::V6(ip) => {
+                value.push(0x02);
+                ip.octets().to_vec()
+            }
+        };
+        value.extend_from_slice(&port.to_be_bytes());
+        value.extend(
+            octets
+                .iter()
+                .zip(key)
+                .map(|(byte, k)| if xor { byte ^ k } else { *byte }),
+        );
+
+        let mut message = Vec::new();
+        message.extend_from_slice(&BINDING_SUCCESS.to_be_bytes());
+        message.extend_from_slice(&u16::try_from(value.len() + 4).unwrap_or(0).to_be_bytes());
+        message.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+        message.extend_from_slice(transaction_id);
+        message.extend_from_slice(&kind.to_be_bytes());
+        message.extend_from_slice(&u16::try_from(value.len()).unwrap_or(0).to_be_bytes());
+        message.extend_from_slice(&value);
+        message
+    }
+
+    #[test]
+    fn decodes_xor_mapped_addresses() -> TestResult {
+        let id = new_transaction_id();
+
+        for addr in ["203.0.113.7:54321", "[2001:db8::7]:3478"] {
+            let addr: SocketAddr = addr.parse().into_diagnostic()?;
+            let response = decode_binding_response(&success(&id, XOR_MAPPED_ADDRESS, addr), &id)?;
+
+            if response.and_then(|r| r.mapped_address) != Some(addr) {
+                return Err(miette!("Expected {addr}, got {response:?}"));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn falls_back_to_mapped_address() -> TestResult {
+        let id = new_transaction_id();
+        let addr: SocketAddr = "203.0.113.7:54321".parse().into_diagnostic()?;
+        let response = decode_binding_response(&success(&id, MAPPED_ADDRESS, addr), &id)?;
+
+        if response.and_then(|r| r.mapped_address) != Some(addr) {
+            return Err(miette!("Expected {addr}, got {response:?}"));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn ignores_responses_to_other_transactions() -> TestResult {
+        let addr: SocketAddr = "203.0.113.7:54321".parse().into_diagnostic()?;
+        let message = success(&new_transaction_id(), XOR_MAPPED_ADDRESS, addr);
+
+        if let Some(response) = decode_binding_response(&message, &new_transaction_id())? {
+            return Err(miette!(
+                "Expected the response to be ignored, got {response:?}"
+            ));
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn binding_request_returns_the_mapped_address() -> TestResult {
+        // Stand in for a STUN server, replying with the address the request came from
+        let server = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .await
+            .into_diagnostic()?;
+        let server_addr = server.local_addr().into_diagnostic()?;
+        let stand_in = tokio::spawn(async move {
+            let mut buffer = [0; 1500];
+            let (len, peer) = server.recv_from(&mut buffer).await?;
+            let id: TransactionId = buffer[8..HEADER_LEN]
+                .try_into()
+                .map_err(std::io::Error::other)?;
+            if buffer[..2] != BINDING_REQUEST.to_be_bytes() || len != HEADER_LEN {
+                return Err(std::io::Error::other("not a bare binding request"));
+            }
+            server
+                .send_to(&success(&id, XOR_MAPPED_ADDRESS, peer), peer)
+                .await?;
+            Ok(peer)
+        });
+
+        let mapped = binding_request(server_addr, SourceAddresses::default()).await?;
+        let peer = stand_in.await.into_diagnostic()?.into_diagnostic()?;
+
+        if mapped != peer {
+            return Err(miette!("Expected {peer}, got {mapped}"));
+        }
+
+        Ok(())
+    }
+}
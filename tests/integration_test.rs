@@ -1,6 +1,7 @@
 use miette::{Result, miette};
 use std::process::Command;
 use std::str;
+use std::thread::JoinHandle;
 
 type TestResult = Result<()>;
 
@@ -40,6 +41,18 @@ fn run_with_args(args: &[&str]) -> Result<String> {
     Ok(stdout.to_string())
 }
 
+// Helper function to run the program with extra environment variables, returning what it printed on stderr
+fn stderr_with_env(args: &[&str], env: &[(&str, &str)]) -> Result<String> {
+    let output = Command::new("cargo")
+        .args(["run", "--"])
+        .args(args)
+        .envs(env.iter().copied())
+        .output()
+        .map_err(|e| miette!("Failed to execute cargo run with args {args:?}: {e}"))?;
+
+    Ok(String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
 #[test]
 fn test_cargo_run() -> TestResult {
     // Run the program with no arguments
@@ -265,3 +278,328 @@ fn test_cargo_run_with_reverse() -> TestResult {
 
     Ok(())
 }
+
+#[test]
+fn test_cargo_run_with_unknown_provider() -> TestResult {
+    // Naming a provider that doesn't exist should fail rather than silently
+    // falling back to the defaults
+    if run_with_args(&["--only-wan", "--provider", "nonsense"]).is_ok() {
+        return Err(miette!("Expected an unknown provider to be rejected"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_unknown_transport() -> TestResult {
+    if run_with_args(&["--only-wan", "--transport", "carrier-pigeon"]).is_ok() {
+        return Err(miette!("Expected an unknown transport to be rejected"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_dns_leak_and_only_local() -> TestResult {
+    if run_with_args(&["--dns-leak", "--only-local"]).is_ok() {
+        return Err(miette!("Expected --dns-leak to conflict with --only-local"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_nat_type_and_proxy() -> TestResult {
+    if run_with_args(&["--nat-type", "--proxy", "socks5://127.0.0.1:9050"]).is_ok() {
+        return Err(miette!("Expected --nat-type to conflict with --proxy"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_cgnat_and_only_6() -> TestResult {
+    if run_with_args(&["--cgnat", "--only-6"]).is_ok() {
+        return Err(miette!("Expected --cgnat to conflict with --only-6"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_only_local_and_only_interface() -> TestResult {
+    let stdout = run_with_args(&["--only-local", "--only-interface", "lo*"])?;
+
+    if stdout.lines().any(|line| !line.contains(" (lo")) {
+        return Err(miette!("Expected only loopback addresses, got: {stdout}"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_only_local_and_exclude_interface() -> TestResult {
+    let stdout = run_with_args(&["--only-local", "--exclude-interface", "lo*"])?;
+
+    if stdout.lines().any(|line| line.contains(" (lo")) {
+        return Err(miette!("Expected no loopback addresses, got: {stdout}"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_only_local_and_scope() -> TestResult {
+    let stdout = run_with_args(&["--only-local", "--scope", "loopback"])?;
+
+    if stdout
+        .lines()
+        .any(|line| !line.starts_with("127.") && !line.starts_with("::1 "))
+    {
+        return Err(miette!("Expected only loopback addresses, got: {stdout}"));
+    }
+
+    Ok(())
+}
+
+// Prefix lengths are only read on Linux
+#[cfg(target_os = "linux")]
+#[test]
+fn test_cargo_run_with_only_local_and_cidr() -> TestResult {
+    let stdout = run_with_args(&["--only-local", "--cidr", "network"])?;
+
+    if !stdout.lines().any(|line| line.starts_with("127.0.0.0/8 ")) {
+        return Err(miette!("Expected the loopback network, got: {stdout}"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_cidr_and_reverse() -> TestResult {
+    if run_with_args(&["--only-local", "--cidr", "--reverse"]).is_ok() {
+        return Err(miette!("Expected --cidr to conflict with --reverse"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_only_local_and_bare() -> TestResult {
+    let stdout = run_with_args(&["--only-local", "--bare"])?;
+
+    if stdout.lines().any(|line| line.contains(' ')) {
+        return Err(miette!("Expected bare addresses, got: {stdout}"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_scope_env_and_dns_leak() -> TestResult {
+    let stderr = stderr_with_env(
+        &["--dns-leak", "--timeout", "0.5"],
+        &[("WHATISMYIP_SCOPE", "global")],
+    )?;
+
+    if stderr.contains("cannot be used with") {
+        return Err(miette!(
+            "Expected WHATISMYIP_SCOPE to be ignored, got: {stderr}"
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_only_interface_env_and_only_wan() -> TestResult {
+    // A filter exported as a default is ignored when no local addresses are printed
+    let stderr = stderr_with_env(
+        &["--only-wan", "--timeout", "0.5"],
+        &[("WHATISMYIP_ONLY_INTERFACE", "eth0")],
+    )?;
+
+    if stderr.contains("cannot be used with") {
+        return Err(miette!(
+            "Expected WHATISMYIP_ONLY_INTERFACE to be ignored, got: {stderr}"
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_unknown_interface() -> TestResult {
+    if run_with_args(&["--only-wan", "--interface", "no-such-interface0"]).is_ok() {
+        return Err(miette!("Expected an unknown interface to be rejected"));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_timeout() -> TestResult {
+    // A STUN server that never answers, so only the timeout can end the lookup
+    let silent = std::net::UdpSocket::bind("127.0.0.1:0")
+        .map_err(|e| miette!("Failed to bind socket: {e}"))?;
+    let server = silent
+        .local_addr()
+        .map_err(|e| miette!("Failed to get socket address: {e}"))?
+        .to_string();
+
+    let output = Command::new("cargo")
+        .args(["run", "--", "--only-wan", "--only-4", "--provider", "stun"])
+        .args(["--stun-server", &server, "--timeout", "0.5"])
+        .output()
+        .map_err(|e| miette!("Failed to execute cargo run: {e}"))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    assert!(!output.status.success(), "Expected the lookup to time out");
+    assert!(
+        stderr.contains("whatismyip::timeout"),
+        "Expected a timeout error, got: {stderr}"
+    );
+
+    Ok(())
+}
+
+/// Stand in for an HTTP echo service, answering a single request with `body`
+fn serve_once(body: &'static str) -> Result<(String, JoinHandle<std::io::Result<()>>)> {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    let listener =
+        TcpListener::bind("127.0.0.1:0").map_err(|e| miette!("Failed to bind listener: {e}"))?;
+    let url = format!(
+        "http://{}/",
+        listener
+            .local_addr()
+            .map_err(|e| miette!("Failed to get listener address: {e}"))?
+    );
+    let server = std::thread::spawn(move || -> std::io::Result<()> {
+        let (mut stream, _) = listener.accept()?;
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request)?;
+        stream.write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n")?;
+        stream.write_all(body.as_bytes())
+    });
+
+    Ok((url, server))
+}
+
+#[test]
+fn test_cargo_run_with_http_provider() -> TestResult {
+    let (url, server) = serve_once(r#"{"ip":"203.0.113.7"}"#)?;
+
+    let stdout = run_with_args(&[
+        "--only-wan",
+        "--only-4",
+        "--provider",
+        "http",
+        "--http-url",
+        &url,
+    ])?;
+    println!("Program output with --provider http: {stdout}");
+
+    server
+        .join()
+        .map_err(|_| miette!("Stand-in server panicked"))?
+        .map_err(|e| miette!("Stand-in server failed: {e}"))?;
+
+    assert_eq!(stdout.trim(), "203.0.113.7");
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_consensus() -> TestResult {
+    let (url, server) = serve_once("203.0.113.7\n")?;
+
+    // With a single provider, that provider is the whole quorum
+    let stdout = run_with_args(&[
+        "--only-wan",
+        "--only-4",
+        "--consensus",
+        "--provider",
+        "http",
+        "--http-url",
+        &url,
+    ])?;
+    println!("Program output with --consensus: {stdout}");
+
+    server
+        .join()
+        .map_err(|_| miette!("Stand-in server panicked"))?
+        .map_err(|e| miette!("Stand-in server failed: {e}"))?;
+
+    assert_eq!(stdout.trim(), "203.0.113.7");
+
+    Ok(())
+}
+
+#[test]
+fn test_cargo_run_with_quorum_requires_consensus() -> TestResult {
+    if run_with_args(&["--only-wan", "--quorum", "2"]).is_ok() {
+        return Err(miette!(
+            "Expected --quorum without --consensus to be rejected"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Stand in for a whoami nameserver, answering every TXT query with `answer`
+fn serve_txt(answer: &'static str) -> Result<String> {
+    use hickory_resolver::proto::op::Message;
+    use hickory_resolver::proto::rr::rdata::TXT;
+    use hickory_resolver::proto::rr::{RData, Record};
+    use std::net::UdpSocket;
+
+    let socket =
+        UdpSocket::bind("127.0.0.1:0").map_err(|e| miette!("Failed to bind socket: {e}"))?;
+    let addr = socket
+        .local_addr()
+        .map_err(|e| miette!("Failed to get socket address: {e}"))?;
+
+    std::thread::spawn(move || -> std::io::Result<()> {
+        let mut buffer = [0; 512];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buffer)?;
+            let request = Message::from_vec(&buffer[..len]).map_err(std::io::Error::other)?;
+            let mut response = Message::response(request.metadata.id, request.metadata.op_code);
+            for query in request.queries {
+                response.add_answer(Record::from_rdata(
+                    query.name().clone(),
+                    0,
+                    RData::TXT(TXT::new(vec![answer.to_string()])),
+                ));
+                response.add_query(query);
+            }
+            let bytes = response.to_vec().map_err(std::io::Error::other)?;
+            socket.send_to(&bytes, peer)?;
+        }
+    });
+
+    Ok(addr.to_string())
+}
+
+#[test]
+fn test_cargo_run_with_wan_nameserver_and_record() -> TestResult {
+    let nameserver = serve_txt("198.51.100.9")?;
+
+    let stdout = run_with_args(&[
+        "--only-wan",
+        "--only-4",
+        "--provider",
+        "google",
+        "--wan-nameserver",
+        &nameserver,
+        "--wan-record",
+        "whoami.example.",
+    ])?;
+    println!("Program output with --wan-nameserver: {stdout}");
+
+    assert_eq!(stdout.trim(), "198.51.100.9");
+
+    Ok(())
+}